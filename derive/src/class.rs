@@ -0,0 +1,432 @@
+//! The `#[lua_class]` and `#[lua_methods]` attributes.
+
+use proc_macro2::{TokenStream as Tokens, Span};
+use syn::{Attribute, DeriveInput, FnArg, Ident, ImplItem, ImplItemFn, ItemImpl, LitStr,
+    ReceiverKind, ReturnType, Type, PathArguments, GenericArgument};
+use syn::spanned::Spanned;
+
+/// Options given with `#[lua(...)]` on a method.
+struct MethodAttrs {
+    name: Option<String>,
+    skip: bool,
+    meta: Option<Ident>,
+    getter: bool,
+    setter: bool,
+}
+
+impl MethodAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<MethodAttrs> {
+        let mut result = MethodAttrs {
+            name: None,
+            skip: false,
+            meta: None,
+            getter: false,
+            setter: false,
+        };
+        for attr in attrs {
+            if !attr.path().is_ident("lua") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    result.name = Some(name.value());
+                } else if meta.path.is_ident("skip") {
+                    result.skip = true;
+                } else if meta.path.is_ident("meta") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    result.meta = Some(name.parse()?);
+                } else if meta.path.is_ident("getter") {
+                    result.getter = true;
+                } else if meta.path.is_ident("setter") {
+                    result.setter = true;
+                } else {
+                    return Err(meta.error("unknown lua attribute, expected one of `name`, \
+                        `skip`, `meta`, `getter` or `setter`"));
+                }
+                Ok(())
+            })?;
+        }
+        let kinds = [result.meta.is_some(), result.getter, result.setter];
+        if kinds.iter().filter(|&&kind| kind).count() > 1 {
+            return Err(syn::Error::new(Span::call_site(),
+                "only one of `meta`, `getter` and `setter` can be used on a method"));
+        }
+        Ok(result)
+    }
+}
+
+/// How a method takes its receiver.
+#[derive(PartialEq)]
+enum Receiver {
+    None,
+    Ref,
+    Mut,
+}
+
+/// How an argument is taken from Lua.
+enum Arg {
+    /// Converted with `FromLuaExt`
+    Value(Box<Type>),
+    /// A reference to another value of the same class
+    SelfRef,
+    /// A mutable reference to another value of the same class
+    SelfMut,
+}
+
+/// How a return value is given to Lua.
+enum Ret {
+    Unit,
+    /// A new value of the class
+    SelfValue,
+    /// Converted with `ToLuaExt`
+    Value,
+}
+
+/// The `LuaClass` method a closure is generated for.
+#[derive(PartialEq)]
+enum Kind {
+    /// A method, metamethod or function that returns any number of values
+    Normal,
+    /// A function that returns a new value of the class
+    Constructor,
+    Getter,
+    Setter,
+}
+
+/// A method of a `#[lua_methods]` impl block.
+struct Method {
+    ident: Ident,
+    receiver: Receiver,
+    context: bool,
+    args: Vec<Arg>,
+    ret: Ret,
+    fallible: bool,
+}
+
+/// Check if a type is `Self` or the type the methods are implemented for.
+fn is_self_type(ty: &Type, self_ident: &Ident) -> bool {
+    match *ty {
+        Type::Path(ref path) if path.qself.is_none() => {
+            path.path.is_ident("Self") || path.path.is_ident(self_ident)
+        },
+        _ => false,
+    }
+}
+
+/// Check if a type is a mutable reference to a `Context`.
+fn is_context(ty: &Type) -> bool {
+    match *ty {
+        Type::Reference(ref reference) if reference.mutability.is_some() => {
+            match *reference.elem {
+                Type::Path(ref path) => path.path.segments.last()
+                    .map(|segment| segment.ident == "Context")
+                    .unwrap_or(false),
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Get the type of the value of a `Result`, if the given type is one.
+fn result_value(ty: &Type) -> Option<&Type> {
+    let path = match *ty {
+        Type::Path(ref path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    match segment.arguments {
+        PathArguments::AngleBracketed(ref args) => match args.args.first() {
+            Some(GenericArgument::Type(ref ty)) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_unit(ty: &Type) -> bool {
+    match *ty {
+        Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+        _ => false,
+    }
+}
+
+impl Method {
+    fn parse(item: &ImplItemFn, self_ident: &Ident) -> syn::Result<Method> {
+        let mut receiver = Receiver::None;
+        let mut context = false;
+        let mut args = Vec::new();
+        for (i, input) in item.sig.inputs.iter().enumerate() {
+            match *input {
+                FnArg::Receiver(ref recv) => {
+                    receiver = match recv.kind {
+                        ReceiverKind::Reference(_, _, None) => Receiver::Ref,
+                        ReceiverKind::Reference(_, _, Some(_)) => Receiver::Mut,
+                        _ => return Err(syn::Error::new(recv.span(),
+                            "Lua methods must take `&self` or `&mut self`")),
+                    };
+                },
+                FnArg::Typed(ref pat) => {
+                    let first = i == 0 || (i == 1 && receiver != Receiver::None);
+                    if first && is_context(&pat.ty) {
+                        context = true;
+                        continue;
+                    }
+                    let arg = match *pat.ty {
+                        Type::Reference(ref reference)
+                                if is_self_type(&reference.elem, self_ident) => {
+                            match reference.mutability {
+                                Some(_) => Arg::SelfMut,
+                                None => Arg::SelfRef,
+                            }
+                        },
+                        Type::Reference(ref reference) => {
+                            return Err(syn::Error::new(reference.span(),
+                                "Lua methods can only take references to their own type"));
+                        },
+                        ref ty => Arg::Value(Box::new(ty.clone())),
+                    };
+                    args.push(arg);
+                },
+            }
+        }
+        let (ret, fallible) = match item.sig.output {
+            ReturnType::Default => (Ret::Unit, false),
+            ReturnType::Type(_, ref ty) => {
+                let (ty, fallible) = match result_value(ty) {
+                    Some(value) => (value, true),
+                    None => (&**ty, false),
+                };
+                let ret = if is_unit(ty) {
+                    Ret::Unit
+                } else if is_self_type(ty, self_ident) {
+                    Ret::SelfValue
+                } else {
+                    Ret::Value
+                };
+                (ret, fallible)
+            },
+        };
+        Ok(Method {
+            ident: item.sig.ident.clone(),
+            receiver,
+            context,
+            args,
+            ret,
+            fallible,
+        })
+    }
+
+    /// Generate a closure for the given `LuaClass` method.
+    fn closure(&self, full_name: &Tokens, kind: Kind) -> Tokens {
+        let ident = &self.ident;
+        let mut names = Vec::new();
+        let mut types = Vec::new();
+        let mut prelude = Vec::new();
+        let mut call_args = Vec::new();
+        if self.receiver != Receiver::None {
+            call_args.push(quote!(__this));
+        }
+        if self.context {
+            call_args.push(quote!(__context));
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            let name = Ident::new(&format!("__arg{}", i), Span::call_site());
+            let position = i + 1;
+            let borrow = |method: Tokens| quote! {
+                let #name = match #name.#method::<Self>(__context) {
+                    Ok(value) => value,
+                    Err(err) => return ::luaext::error::new_luaresult_err(err.get_type(),
+                        format!("{}: value #{}: {}", #full_name, #position, err.get_message())),
+                };
+            };
+            match *arg {
+                Arg::Value(ref ty) => {
+                    types.push(quote!(::luaext::convert::Convert<#ty>));
+                    prelude.push(quote!(let #name = #name.0;));
+                    call_args.push(quote!(#name));
+                },
+                Arg::SelfRef => {
+                    types.push(quote!(::luaext::types::LuaUserdata));
+                    prelude.push(borrow(quote!(try_borrow)));
+                    call_args.push(quote!(&*#name));
+                },
+                Arg::SelfMut => {
+                    types.push(quote!(::luaext::types::LuaUserdata));
+                    prelude.push(borrow(quote!(try_borrow_mut)));
+                    call_args.push(quote!(&mut *#name));
+                },
+            }
+            names.push(name);
+        }
+        let call = quote!(Self::#ident(#(#call_args),*));
+        let call = if self.fallible {
+            quote! {
+                match #call {
+                    Ok(value) => value,
+                    Err(err) => return ::luaext::error::new_luaresult_err(err.get_type(),
+                        format!("{}: {}", #full_name, err.get_message())),
+                }
+            }
+        } else {
+            call
+        };
+        let ret = match (&self.ret, kind == Kind::Normal) {
+            (_, _) if kind == Kind::Constructor => quote!(Ok(__ret)),
+            (&Ret::Unit, _) => quote!(Ok(())),
+            (&Ret::SelfValue, true) => quote!(Ok((__context.push_userdata(__ret),))),
+            (&Ret::SelfValue, false) => quote!(Ok(__context.push_userdata(__ret))),
+            (&Ret::Value, true) => quote!(Ok((::luaext::convert::Convert(__ret),))),
+            (&Ret::Value, false) => quote!(Ok(::luaext::convert::Convert(__ret))),
+        };
+        let this = match self.receiver {
+            Receiver::None => quote!(),
+            _ => quote!(__this,),
+        };
+        let args = match kind {
+            Kind::Getter => quote!(),
+            Kind::Setter => quote!(#(#names)*: #(#types)*),
+            _ => quote!((#(#names,)*): (#(#types,)*)),
+        };
+        quote! {
+            |__context: &mut ::luaext::context::Context, #this #args| {
+                #(#prelude)*
+                #[allow(clippy::let_unit_value)]
+                let __ret = #call;
+                #ret
+            }
+        }
+    }
+}
+
+/// Get the Lua name of a class from the arguments of `#[lua_class]`.
+fn class_name(attr: Tokens, ident: &Ident) -> syn::Result<String> {
+    let mut name = ident.to_string();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            let value: LitStr = meta.value()?.parse()?;
+            name = value.value();
+            Ok(())
+        } else {
+            Err(meta.error("unknown lua_class attribute, expected `name`"))
+        }
+    });
+    syn::parse::Parser::parse2(parser, attr)?;
+    Ok(name)
+}
+
+pub fn expand_lua_class(attr: Tokens, input: DeriveInput) -> syn::Result<Tokens> {
+    let ident = &input.ident;
+    let name = class_name(attr, ident)?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        #input
+
+        impl #impl_generics ::luaext::userdata::LuaUserData for #ident #ty_generics #where_clause {
+            fn type_name() -> &'static str {
+                #name
+            }
+        }
+    })
+}
+
+pub fn expand_lua_methods(mut input: ItemImpl) -> syn::Result<Tokens> {
+    if input.trait_.is_some() {
+        return Err(syn::Error::new(input.span(), "lua_methods must be used on an inherent impl"));
+    }
+    let self_ident = match *input.self_ty {
+        Type::Path(ref path) if path.qself.is_none() && !path.path.segments.is_empty() => {
+            path.path.segments.last().unwrap().ident.clone()
+        },
+        _ => return Err(syn::Error::new(input.self_ty.span(),
+            "lua_methods must be used on an impl for a named type")),
+    };
+    let mut calls = Vec::new();
+    for item in &mut input.items {
+        let item = match *item {
+            ImplItem::Fn(ref mut item) => item,
+            _ => continue,
+        };
+        let attrs = MethodAttrs::parse(&item.attrs)?;
+        item.attrs.retain(|attr| !attr.path().is_ident("lua"));
+        if attrs.skip {
+            continue;
+        }
+        let method = Method::parse(item, &self_ident)?;
+        let ident_name = method.ident.to_string();
+        let span = item.sig.span();
+        let type_name = quote!(<Self as ::luaext::userdata::LuaUserData>::type_name());
+        let call = if let Some(ref meta) = attrs.meta {
+            let full_name = quote!(format!("{}.{}", #type_name,
+                ::luaext::class::MetaMethod::#meta.name()));
+            let closure = method.closure(&full_name, Kind::Normal);
+            let meta = quote!(::luaext::class::MetaMethod::#meta);
+            match method.receiver {
+                Receiver::None => quote!(.meta_function(#meta, #closure)),
+                Receiver::Ref => quote!(.meta_method(#meta, #closure)),
+                Receiver::Mut => quote!(.meta_method_mut(#meta, #closure)),
+            }
+        } else if attrs.getter {
+            if method.receiver != Receiver::Ref || !method.args.is_empty() {
+                return Err(syn::Error::new(span, "getters must only take `&self`"));
+            }
+            let name = attrs.name.unwrap_or(ident_name);
+            let full_name = quote!(format!("{}.{}", #type_name, #name));
+            let closure = method.closure(&full_name, Kind::Getter);
+            quote!(.getter(#name, #closure))
+        } else if attrs.setter {
+            match (&method.receiver, &method.args[..]) {
+                (&Receiver::Mut, &[Arg::Value(_)]) => {},
+                _ => return Err(syn::Error::new(span, "setters must take `&mut self` and a value")),
+            }
+            let name = attrs.name.unwrap_or_else(|| {
+                ident_name.trim_start_matches("set_").to_string()
+            });
+            let full_name = quote!(format!("{}.{}", #type_name, #name));
+            let closure = method.closure(&full_name, Kind::Setter);
+            quote!(.setter(#name, #closure))
+        } else {
+            let name = attrs.name.unwrap_or(ident_name);
+            let separator = match method.receiver {
+                Receiver::None => ".",
+                _ => ":",
+            };
+            let full_name = quote!(format!("{}{}{}", #type_name, #separator, #name));
+            match (&method.receiver, &method.ret) {
+                (&Receiver::None, &Ret::SelfValue) => {
+                    let closure = method.closure(&full_name, Kind::Constructor);
+                    quote!(.constructor(#name, #closure))
+                },
+                (&Receiver::None, _) => {
+                    let closure = method.closure(&full_name, Kind::Normal);
+                    quote!(.function(#name, #closure))
+                },
+                (&Receiver::Ref, _) => {
+                    let closure = method.closure(&full_name, Kind::Normal);
+                    quote!(.method(#name, #closure))
+                },
+                (&Receiver::Mut, _) => {
+                    let closure = method.closure(&full_name, Kind::Normal);
+                    quote!(.method_mut(#name, #closure))
+                },
+            }
+        };
+        calls.push(call);
+    }
+    let self_ty = &input.self_ty;
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        #input
+
+        impl #impl_generics ::luaext::class::LuaMethods for #self_ty #where_clause {
+            fn add_methods(class: ::luaext::class::LuaClass<Self>)
+                    -> ::luaext::class::LuaClass<Self> {
+                class #(#calls)*
+            }
+        }
+    })
+}
@@ -0,0 +1,556 @@
+//! Derive macros for the `ToLua` and `FromLua` traits of the `lua` crate, and attributes that
+//! expose Rust types to Lua as classes.
+//!
+//! Structs with named fields become tables with a field for each struct field, tuple structs
+//! become array tables, newtype structs are converted the same way as the type they wrap, and
+//! unit structs become nil. Unit enum variants become the variant's name, and all other enum
+//! variants become a table with a single field, named after the variant, holding the variant's
+//! data. Fields are read from tables with raw gets, so `__index` metamethods are not called.
+//!
+//! Types that derive `ToLua` and `FromLua` also implement `ToLuaExt` and `FromLuaExt`, so they
+//! can be used inside standard collections converted with `Convert`. These impls are generated
+//! by the derives, so they must not be written by hand as well.
+//!
+//! The generated code refers to the `lua` crate through `luaext`, so `luaext` must be a
+//! dependency of the crate that uses these macros.
+//!
+//! # Attributes
+//!
+//! * `#[lua(rename = "name")]` on a field or variant uses a different name in Lua.
+//! * `#[lua(default)]` on a field uses `Default::default()` if the field is nil or missing, and
+//!   `#[lua(default = "path")]` calls the given function instead.
+//! * `#[lua(skip)]` on a field never converts it to Lua, and always uses its default value when
+//!   converting from Lua.
+//! * `#[lua(flatten)]` on a field stores the fields of the field's table in the same table as
+//!   the other fields, instead of in a nested table.
+//!
+//! # Classes
+//!
+//! `#[lua_class]` implements `LuaUserData` for a type, and `#[lua_methods]` on an impl block
+//! implements `LuaMethods`, which adds every function of the block to a `LuaClass`:
+//!
+//! * Functions that take `&self` or `&mut self` become methods, and other functions become
+//!   static functions of the class table. Static functions that return `Self` are constructors.
+//! * Arguments are converted with `FromLuaExt`, and return values with `ToLuaExt`, except that
+//!   `Self` is pushed as a new userdata and `&Self` arguments borrow another value of the class.
+//!   A `&mut Context` argument, right after the receiver, is given the calling context.
+//! * Functions that return `luaext::error::Result` raise their errors in Lua.
+//! * `#[lua(name = "name")]` uses a different name in Lua, and `#[lua(skip)]` leaves out a
+//!   function.
+//! * `#[lua(getter)]` and `#[lua(setter)]` declare properties; a setter named `set_x` sets the
+//!   property `x`.
+//! * `#[lua(meta = "ToString")]` makes a function the metamethod of the given `MetaMethod`.
+//!
+//! Errors name the class and the function that failed, as in `Rect:grow`.
+//!
+//! # Examples
+//!
+//! ```
+//! #[macro_use]
+//! extern crate luaext_derive;
+//! extern crate luaext;
+//!
+//! # use luaext::lua::State;
+//! # use luaext::context::Context;
+//! #[derive(ToLua, FromLua)]
+//! struct Point {
+//!     x: f64,
+//!     y: f64,
+//!     #[lua(default)]
+//!     z: f64,
+//! }
+//!
+//! # fn main() {
+//! # let mut state = State::new();
+//! # let mut context = Context::new(&mut state);
+//! context.do_string("point = {x = 1, y = 2}").unwrap();
+//! let point: Point = context.push_global("point").get_value(&mut context).unwrap();
+//! assert_eq!(point.z, 0.0);
+//! # }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::{TokenStream as Tokens, Span};
+use syn::{DeriveInput, Data, Fields, Field, Variant, Ident, LitStr, ExprPath, Generics};
+use syn::spanned::Spanned;
+
+mod class;
+
+/// Derive `lua::ToLua` for a struct or enum.
+#[proc_macro_derive(ToLua, attributes(lua))]
+pub fn derive_to_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand_to_lua(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derive `lua::FromLua` for a struct or enum.
+#[proc_macro_derive(FromLua, attributes(lua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand_from_lua(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Implement `LuaUserData` for a struct or enum, named after the type in Lua.
+///
+/// The name can be changed with `#[lua_class(name = "Name")]`.
+#[proc_macro_attribute]
+pub fn lua_class(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    class::expand_lua_class(attr.into(), input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Implement `LuaMethods` with the functions of an impl block.
+#[proc_macro_attribute]
+pub fn lua_methods(attr: TokenStream, input: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let err = syn::Error::new(Span::call_site(), "lua_methods does not take arguments");
+        return err.to_compile_error().into();
+    }
+    let input = syn::parse_macro_input!(input as syn::ItemImpl);
+    class::expand_lua_methods(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// How to fill in a field that is missing from a table.
+enum FieldDefault {
+    /// The field is required
+    None,
+    /// Use `Default::default()`
+    Trait,
+    /// Call the given function
+    Path(ExprPath),
+}
+
+/// Options given with `#[lua(...)]` on a field or variant.
+struct Attrs {
+    rename: Option<String>,
+    default: FieldDefault,
+    skip: bool,
+    flatten: bool,
+}
+
+impl Attrs {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Attrs> {
+        let mut result = Attrs {
+            rename: None,
+            default: FieldDefault::None,
+            skip: false,
+            flatten: false,
+        };
+        for attr in attrs {
+            if !attr.path().is_ident("lua") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    let name: LitStr = meta.value()?.parse()?;
+                    result.rename = Some(name.value());
+                } else if meta.path.is_ident("default") {
+                    result.default = if meta.input.peek(syn::Token![=]) {
+                        let path: LitStr = meta.value()?.parse()?;
+                        FieldDefault::Path(path.parse()?)
+                    } else {
+                        FieldDefault::Trait
+                    };
+                } else if meta.path.is_ident("skip") {
+                    result.skip = true;
+                } else if meta.path.is_ident("flatten") {
+                    result.flatten = true;
+                } else {
+                    return Err(meta.error("unknown lua attribute, expected one of `rename`, \
+                        `default`, `skip` or `flatten`"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(result)
+    }
+
+    /// The expression used for this field when its value is not available.
+    fn default_value(&self) -> Tokens {
+        match self.default {
+            FieldDefault::Path(ref path) => quote!(#path()),
+            _ => quote!(::std::default::Default::default()),
+        }
+    }
+}
+
+/// Get the name a field or variant has in Lua.
+fn lua_name(ident: &Ident, attrs: &Attrs) -> String {
+    match attrs.rename {
+        Some(ref name) => name.clone(),
+        None => ident.to_string(),
+    }
+}
+
+/// Add a bound on the given trait to every type parameter.
+fn add_bounds(generics: &mut Generics, bound: Tokens) {
+    let params: Vec<Ident> = generics.type_params().map(|param| param.ident.clone()).collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause.predicates.push(syn::parse_quote!(#param: #bound));
+    }
+}
+
+/// The variable names used to bind the fields of a struct or variant.
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    fields.iter()
+        .enumerate()
+        .map(|(i, field)| match field.ident {
+            Some(ref ident) => ident.clone(),
+            None => Ident::new(&format!("__field{}", i), Span::call_site()),
+        })
+        .collect()
+}
+
+/// Pattern that binds every field of a struct or variant by reference.
+fn binding_pattern(path: Tokens, fields: &Fields) -> Tokens {
+    let names = bindings(fields);
+    match *fields {
+        Fields::Named(_) => quote!(#path { #(ref #names),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(ref #names),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn check_unsupported(field: &Field, attrs: &Attrs, named: bool) -> syn::Result<()> {
+    if !named && (attrs.rename.is_some() || attrs.flatten) {
+        return Err(syn::Error::new(field.span(),
+            "`rename` and `flatten` can only be used on named fields"));
+    }
+    if attrs.skip && attrs.flatten {
+        return Err(syn::Error::new(field.span(), "a field can not be both skipped and flattened"));
+    }
+    Ok(())
+}
+
+/// Push the fields bound to `bindings` as a single value.
+fn push_fields(fields: &Fields) -> syn::Result<Tokens> {
+    let names = bindings(fields);
+    match *fields {
+        Fields::Named(_) => {
+            let mut sets = Vec::new();
+            for (field, name) in fields.iter().zip(names.iter()) {
+                let attrs = Attrs::parse(&field.attrs)?;
+                check_unsupported(field, &attrs, true)?;
+                if attrs.skip {
+                    continue;
+                }
+                if attrs.flatten {
+                    sets.push(quote! {
+                        ::luaext::lua::ToLua::to_lua(#name, state);
+                        if state.is_table(-1) {
+                            state.push_nil();
+                            while state.next(-2) {
+                                state.push_value(-2);
+                                state.insert(-2);
+                                state.raw_set(-5);
+                            }
+                        }
+                        state.pop(1);
+                    });
+                } else {
+                    let key = lua_name(field.ident.as_ref().unwrap(), &attrs);
+                    sets.push(quote! {
+                        ::luaext::lua::ToLua::to_lua(#name, state);
+                        state.set_field(-2, #key);
+                    });
+                }
+            }
+            let count = sets.len() as i32;
+            Ok(quote! {
+                state.check_stack_msg(5, "not enough stack space to push table");
+                state.create_table(0, #count);
+                #(#sets)*
+            })
+        },
+        Fields::Unnamed(ref unnamed) if unnamed.unnamed.len() == 1 => {
+            let field = &unnamed.unnamed[0];
+            let attrs = Attrs::parse(&field.attrs)?;
+            check_unsupported(field, &attrs, false)?;
+            let name = &names[0];
+            Ok(quote!(::luaext::lua::ToLua::to_lua(#name, state);))
+        },
+        Fields::Unnamed(_) => {
+            let mut sets = Vec::new();
+            for (i, (field, name)) in fields.iter().zip(names.iter()).enumerate() {
+                let attrs = Attrs::parse(&field.attrs)?;
+                check_unsupported(field, &attrs, false)?;
+                let n = i as i64 + 1;
+                if attrs.skip {
+                    continue;
+                }
+                sets.push(quote! {
+                    ::luaext::lua::ToLua::to_lua(#name, state);
+                    state.raw_seti(-2, #n);
+                });
+            }
+            let count = fields.len() as i32;
+            Ok(quote! {
+                state.check_stack_msg(2, "not enough stack space to push table");
+                state.create_table(#count, 0);
+                #(#sets)*
+            })
+        },
+        Fields::Unit => Ok(quote!(state.push_nil();)),
+    }
+}
+
+/// Convert the single field pushed on top of the stack.
+fn read_field(field: &Field, attrs: &Attrs) -> Tokens {
+    let ty = &field.ty;
+    let convert = quote!(<#ty as ::luaext::lua::FromLua>::from_lua(state, -1));
+    let value = match attrs.default {
+        FieldDefault::None => quote!(#convert),
+        _ => {
+            let default = attrs.default_value();
+            quote!(if state.is_nil(-1) { Some(#default) } else { #convert })
+        },
+    };
+    quote! {{
+        let value = #value;
+        state.pop(1);
+        match value {
+            Some(value) => value,
+            None => return None,
+        }
+    }}
+}
+
+/// Build `path` from the value at the absolute index `index`, returning None from the
+/// enclosing function if the value can not be converted.
+fn read_fields(path: Tokens, fields: &Fields) -> syn::Result<Tokens> {
+    match *fields {
+        Fields::Named(_) => {
+            let mut inits = Vec::new();
+            for field in fields.iter() {
+                let attrs = Attrs::parse(&field.attrs)?;
+                check_unsupported(field, &attrs, true)?;
+                let ident = field.ident.as_ref().unwrap();
+                let ty = &field.ty;
+                let value = if attrs.skip {
+                    attrs.default_value()
+                } else if attrs.flatten {
+                    quote! {
+                        match <#ty as ::luaext::lua::FromLua>::from_lua(state, index) {
+                            Some(value) => value,
+                            None => return None,
+                        }
+                    }
+                } else {
+                    let key = lua_name(ident, &attrs);
+                    let read = read_field(field, &attrs);
+                    // a raw get, since an __index metamethod could raise an error
+                    quote! {{
+                        state.push_string(#key);
+                        state.raw_get(index);
+                        #read
+                    }}
+                };
+                inits.push(quote!(#ident: #value));
+            }
+            Ok(quote! {{
+                if !state.is_table(index) {
+                    return None;
+                }
+                state.check_stack_msg(2, "not enough stack space to read table");
+                #path { #(#inits),* }
+            }})
+        },
+        Fields::Unnamed(ref unnamed) if unnamed.unnamed.len() == 1 => {
+            let field = &unnamed.unnamed[0];
+            let attrs = Attrs::parse(&field.attrs)?;
+            check_unsupported(field, &attrs, false)?;
+            let ty = &field.ty;
+            Ok(quote! {
+                match <#ty as ::luaext::lua::FromLua>::from_lua(state, index) {
+                    Some(value) => #path(value),
+                    None => return None,
+                }
+            })
+        },
+        Fields::Unnamed(_) => {
+            let mut inits = Vec::new();
+            for (i, field) in fields.iter().enumerate() {
+                let attrs = Attrs::parse(&field.attrs)?;
+                check_unsupported(field, &attrs, false)?;
+                let n = i as i64 + 1;
+                let value = if attrs.skip {
+                    attrs.default_value()
+                } else {
+                    let read = read_field(field, &attrs);
+                    quote! {{
+                        state.raw_geti(index, #n);
+                        #read
+                    }}
+                };
+                inits.push(value);
+            }
+            Ok(quote! {{
+                if !state.is_table(index) {
+                    return None;
+                }
+                state.check_stack_msg(1, "not enough stack space to read table");
+                #path(#(#inits),*)
+            }})
+        },
+        Fields::Unit => Ok(quote! {{
+            if !state.is_none_or_nil(index) {
+                return None;
+            }
+            #path
+        }}),
+    }
+}
+
+fn variant_name(variant: &Variant) -> syn::Result<String> {
+    let attrs = Attrs::parse(&variant.attrs)?;
+    Ok(lua_name(&variant.ident, &attrs))
+}
+
+fn expand_to_lua(mut input: DeriveInput) -> syn::Result<Tokens> {
+    add_bounds(&mut input.generics, quote!(::luaext::lua::ToLua));
+    let ident = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let pattern = binding_pattern(quote!(#ident), &data.fields);
+            let push = push_fields(&data.fields)?;
+            quote! {
+                let #pattern = *self;
+                #push
+            }
+        },
+        Data::Enum(ref data) => {
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let variant_ident = &variant.ident;
+                let name = variant_name(variant)?;
+                let pattern = binding_pattern(quote!(#ident::#variant_ident), &variant.fields);
+                let push = match variant.fields {
+                    Fields::Unit => quote!(state.push_string(#name);),
+                    ref fields => {
+                        let push = push_fields(fields)?;
+                        quote! {
+                            state.check_stack_msg(1, "not enough stack space to push table");
+                            state.create_table(0, 1);
+                            #push
+                            state.set_field(-2, #name);
+                        }
+                    },
+                };
+                arms.push(quote!(#pattern => { #push }));
+            }
+            quote! {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        },
+        Data::Union(_) => {
+            return Err(syn::Error::new(input.span(), "ToLua can not be derived for unions"));
+        },
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::luaext::lua::ToLua for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn to_lua(&self, state: &mut ::luaext::lua::State) {
+                #body
+            }
+        }
+
+        impl #impl_generics ::luaext::convert::ToLuaExt for #ident #ty_generics #where_clause {
+            fn to_lua_ext(&self, state: &mut ::luaext::lua::State) {
+                ::luaext::lua::ToLua::to_lua(self, state)
+            }
+        }
+    })
+}
+
+fn expand_from_lua(mut input: DeriveInput) -> syn::Result<Tokens> {
+    add_bounds(&mut input.generics, quote!(::luaext::lua::FromLua));
+    let ident = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let read = read_fields(quote!(#ident), &data.fields)?;
+            quote!(Some(#read))
+        },
+        Data::Enum(ref data) => {
+            let mut unit_arms = Vec::new();
+            let mut tagged = Vec::new();
+            for variant in &data.variants {
+                let variant_ident = &variant.ident;
+                let name = variant_name(variant)?;
+                match variant.fields {
+                    Fields::Unit => unit_arms.push(quote! {
+                        Some(#name) => return Some(#ident::#variant_ident),
+                    }),
+                    ref fields => {
+                        let read = read_fields(quote!(#ident::#variant_ident), fields)?;
+                        tagged.push(quote! {
+                            if state.get_field(outer, #name) != ::luaext::lua::Type::Nil {
+                                let index = state.get_top();
+                                let read = |state: &mut ::luaext::lua::State| {
+                                    Some(#read)
+                                };
+                                let value = read(state);
+                                state.pop(1);
+                                return value;
+                            }
+                            state.pop(1);
+                        });
+                    },
+                }
+            }
+            quote! {
+                if state.type_of(index) == Some(::luaext::lua::Type::String) {
+                    match state.to_str_in_place(index) {
+                        #(#unit_arms)*
+                        _ => return None,
+                    }
+                }
+                if !state.is_table(index) {
+                    return None;
+                }
+                let outer = index;
+                state.check_stack_msg(1, "not enough stack space to read table");
+                #(#tagged)*
+                None
+            }
+        },
+        Data::Union(_) => {
+            return Err(syn::Error::new(input.span(), "FromLua can not be derived for unions"));
+        },
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::luaext::lua::FromLua for #ident #ty_generics #where_clause {
+            #[allow(unused_variables, unreachable_code)]
+            fn from_lua(state: &mut ::luaext::lua::State, index: ::luaext::lua::Index)
+                    -> Option<Self> {
+                let index = state.abs_index(index);
+                #body
+            }
+        }
+
+        impl #impl_generics ::luaext::convert::FromLuaExt for #ident #ty_generics #where_clause {
+            fn from_lua_ext(state: &mut ::luaext::lua::State, index: ::luaext::lua::Index)
+                    -> Option<Self> {
+                ::luaext::lua::FromLua::from_lua(state, index)
+            }
+        }
+    })
+}
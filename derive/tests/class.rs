@@ -0,0 +1,132 @@
+#[macro_use]
+extern crate luaext_derive;
+extern crate luaext;
+
+use luaext::Lua;
+use luaext::class;
+use luaext::context::Context;
+use luaext::error;
+
+#[derive(ToLua, FromLua, Clone, Copy, Debug, PartialEq)]
+struct Size {
+    width: f64,
+    height: f64,
+}
+
+#[lua_class(name = "Rect")]
+#[derive(Clone, Debug, PartialEq)]
+struct Rectangle {
+    size: Size,
+    label: String,
+}
+
+#[lua_methods]
+impl Rectangle {
+    fn new(width: f64, height: f64) -> Rectangle {
+        Rectangle { size: Size { width, height }, label: String::new() }
+    }
+
+    fn area(&self) -> f64 {
+        self.size.width * self.size.height
+    }
+
+    fn grow(&mut self, amount: f64) {
+        self.size.width += amount;
+        self.size.height += amount;
+    }
+
+    fn fits_in(&self, other: &Rectangle) -> bool {
+        self.size.width <= other.size.width && self.size.height <= other.size.height
+    }
+
+    #[lua(name = "tags")]
+    fn label_parts(&self) -> Vec<String> {
+        self.label.split(' ').map(|s| s.to_string()).collect()
+    }
+
+    fn scaled(&self, factor: u8) -> Rectangle {
+        let factor = f64::from(factor);
+        Rectangle::new(self.size.width * factor, self.size.height * factor)
+    }
+
+    fn resize(&mut self, size: Size) -> error::Result<()> {
+        if size.width < 0.0 || size.height < 0.0 {
+            return error::new_luaresult_err(error::LuaErrorType::RuntimeError,
+                "size can not be negative".to_string());
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    fn count_globals(context: &mut Context) -> i64 {
+        context.push_global("_G").get_value::<luaext::types::LuaTable>(context)
+            .map(|_| 1)
+            .unwrap_or(0)
+    }
+
+    #[lua(getter)]
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    #[lua(getter)]
+    fn label(&self) -> String {
+        self.label.clone()
+    }
+
+    #[lua(setter)]
+    fn set_label(&mut self, label: String) {
+        self.label = label;
+    }
+
+    #[lua(meta = "ToString")]
+    fn describe(&self) -> String {
+        format!("{}x{}", self.size.width, self.size.height)
+    }
+
+    #[lua(meta = "Eq")]
+    fn same(&self, other: &Rectangle) -> bool {
+        self == other
+    }
+
+    #[lua(skip)]
+    #[allow(dead_code)]
+    fn internal(&self) -> *const Rectangle {
+        self
+    }
+}
+
+#[test]
+fn test_lua_methods() {
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    class::register::<Rectangle>(&mut context);
+    context.do_string("
+        local r = Rect.new(2, 3)
+        assert(r:area() == 6)
+        r:grow(1)
+        assert(tostring(r) == '3x4' and r.size.width == 3)
+        assert(r:fits_in(r:scaled(2)) and not r:scaled(2):fits_in(r))
+        r.label = 'big blue'
+        assert(r:tags()[2] == 'blue')
+        r:resize({width = 1, height = 1})
+        assert(r.size.height == 1 and r ~= Rect.new(1, 1) and Rect.new(1, 1) == Rect.new(1, 1))
+        assert(Rect.count_globals() == 1)
+    ").unwrap();
+}
+
+#[test]
+fn test_lua_methods_errors() {
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    class::register::<Rectangle>(&mut context);
+    let err = context.do_string("Rect.new(1, 1):resize({width = -1, height = 0})").unwrap_err();
+    assert_eq!(err.get_message(), "Rect:resize: size can not be negative");
+    let err = context.do_string("Rect.new(1, 1):scaled(300)").unwrap_err();
+    assert!(err.get_message().starts_with("Rect:scaled: value #1 (a number value) can not be \
+        converted to"), "{}", err);
+    let err = context.do_string("Rect.new(1, 1):fits_in(5)").unwrap_err();
+    assert!(err.get_message().starts_with("Rect:fits_in: value #1"), "{}", err);
+    let err = context.do_string("local r = Rect.new(1, 1); r.size = 5").unwrap_err();
+    assert_eq!(err.get_message(), "Rect.size is read-only");
+}
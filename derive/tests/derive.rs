@@ -0,0 +1,109 @@
+#[macro_use]
+extern crate luaext_derive;
+extern crate luaext;
+
+use luaext::lua::State;
+use luaext::context::Context;
+use luaext::convert::Convert;
+
+#[derive(ToLua, FromLua, Debug, PartialEq, Default)]
+struct Position {
+    x: i64,
+    y: i64,
+}
+
+#[derive(ToLua, FromLua, Debug, PartialEq)]
+enum Shape {
+    Empty,
+    #[lua(rename = "dot")]
+    Point(Position),
+    Line(Position, Position),
+    Circle { radius: f64 },
+}
+
+fn default_name() -> String {
+    "unnamed".to_string()
+}
+
+#[derive(ToLua, FromLua, Debug, PartialEq)]
+struct Entity {
+    #[lua(rename = "id")]
+    entity_id: i64,
+    #[lua(default = "default_name")]
+    name: String,
+    #[lua(flatten)]
+    position: Position,
+    shapes: Shapes,
+    #[lua(skip)]
+    cached: i64,
+}
+
+#[derive(ToLua, FromLua, Debug, PartialEq)]
+struct Shapes(Shape, Shape, Shape, Shape);
+
+#[test]
+fn test_derive_struct() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.do_string("entity = {id = 3, x = 1, y = 2, shapes = {'Empty', {dot = {x = 0, y = 0}}, \
+        {Line = {{x = 1, y = 1}, {x = 2, y = 2}}}, {Circle = {radius = 1.5}}}}").unwrap();
+    let entity: Entity = context.push_global("entity").get_value(&mut context).unwrap();
+    assert_eq!(entity, Entity {
+        entity_id: 3,
+        name: "unnamed".to_string(),
+        position: Position { x: 1, y: 2 },
+        shapes: Shapes(Shape::Empty, Shape::Point(Position::default()),
+            Shape::Line(Position { x: 1, y: 1 }, Position { x: 2, y: 2 }),
+            Shape::Circle { radius: 1.5 }),
+        cached: 0,
+    });
+
+    context.set_global("copy", &entity);
+    context.do_string("x = copy.x; id = copy.id; cached = copy.cached; \
+        radius = copy.shapes[4].Circle.radius").unwrap();
+    assert_eq!(Some(1), context.push_global("x").get_value::<i64>(&mut context));
+    assert_eq!(Some(3), context.push_global("id").get_value::<i64>(&mut context));
+    assert!(context.push_global("cached").get_value::<i64>(&mut context).is_none());
+    assert_eq!(Some(1.5), context.push_global("radius").get_value::<f64>(&mut context));
+    let copy: Entity = context.push_global("copy").get_value(&mut context).unwrap();
+    assert_eq!(copy, entity);
+}
+
+#[test]
+fn test_derive_mismatch() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.do_string("a = {x = 1}; b = 'Square'; c = {x = 1, y = 'two'}").unwrap();
+    let top = context.get_state().get_top();
+    assert!(context.push_global("a").get_value::<Position>(&mut context).is_none());
+    assert!(context.push_global("b").get_value::<Shape>(&mut context).is_none());
+    assert!(context.push_global("c").get_value::<Position>(&mut context).is_none());
+    // failed conversions leave nothing behind but the pushed globals
+    assert_eq!(context.get_state().get_top(), top + 3);
+}
+
+#[test]
+fn test_derive_ignores_index() {
+    let mut state = State::new();
+    state.open_libs();
+    let mut context = Context::new(&mut state);
+    context.do_string("position = setmetatable({x = 1}, {__index = function() error('no') end})")
+        .unwrap();
+    // y is missing, and reading it does not call __index
+    assert!(context.push_global("position").get_value::<Position>(&mut context).is_none());
+    context.do_string("position.y = 2").unwrap();
+    let position = context.push_global("position").get_value::<Position>(&mut context);
+    assert_eq!(position, Some(Position { x: 1, y: 2 }));
+}
+
+#[test]
+fn test_derive_in_collections() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    let positions = vec![Position { x: 1, y: 2 }, Position { x: 3, y: 4 }];
+    context.set_global("positions", &Convert(&positions));
+    context.do_string("y = positions[2].y").unwrap();
+    assert_eq!(Some(4), context.push_global("y").get_value::<i64>(&mut context));
+    let copy = context.push_global("positions").get_value::<Convert<Vec<Position>>>(&mut context);
+    assert_eq!(copy.map(Convert::into_inner), Some(positions));
+}
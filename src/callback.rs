@@ -0,0 +1,82 @@
+//! Rust closures that can be called from Lua.
+//!
+//! A closure is boxed and stored in a userdata, which becomes the only upvalue of a C closure
+//! that calls it. The userdata's `__gc` metamethod drops the closure once the Lua function is
+//! collected.
+
+use lua::{State, Index};
+use lua::ffi::{self, lua_State};
+use lua::libc::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use context::Context;
+use types;
+use userdata::{self, LuaUserData, UserdataCell};
+use error;
+
+/// A boxed Rust function that can be called from Lua.
+pub(crate) type RustFunction = Box<dyn Fn(Context) -> error::Result<Index> + Send>;
+
+/// The userdata that holds a `RustFunction`.
+struct Closure(RustFunction);
+
+impl LuaUserData for Closure {
+    fn type_name() -> &'static str {
+        "luaext.closure"
+    }
+}
+
+/// Push a Lua function that calls the given Rust function.
+pub(crate) fn push_closure(context: &mut Context, func: RustFunction) -> types::LuaFunction {
+    push_closure_with_upvalues(context, func, 0)
+}
+
+/// Push a Lua function that calls the given Rust function, and takes the top `n` values of the
+/// stack as its upvalues, starting from the upvalue 2.
+pub(crate) fn push_closure_with_upvalues(context: &mut Context, func: RustFunction, n: Index)
+        -> types::LuaFunction {
+    context.push_userdata(Closure(func));
+    let state = context.get_state();
+    // the userdata has to be the first upvalue
+    state.insert(-(n + 1));
+    // pops the upvalues, so that the userdata is only referenced as an upvalue
+    state.push_closure(Some(call_closure), n + 1);
+    let i = state.get_top();
+    context.handle(i)
+}
+
+/// Call the function stored in the first upvalue, and convert its errors into Lua errors.
+unsafe extern "C" fn call_closure(state: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(state);
+    match call_closure_inner(&mut state) {
+        Ok(nresults) => nresults,
+        Err(message) => {
+            state.push_string(&message);
+            // raising an error skips destructors, so the message has to be dropped first
+            drop(message);
+            state.error()
+        }
+    }
+}
+
+fn call_closure_inner(state: &mut State) -> Result<c_int, String> {
+    let block = state.to_userdata(ffi::lua_upvalueindex(1));
+    let ptr = userdata::userdata_ptr::<UserdataCell<Closure>>(block);
+    // the upvalue is only ever set by push_closure
+    let cell = unsafe { &*ptr };
+    let closure = cell.try_borrow().map_err(|message| message.to_string())?;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| (closure.0)(Context::new(state))));
+    match result {
+        Ok(Ok(nresults)) => Ok(nresults),
+        Ok(Err(err)) => Err(err.to_string()),
+        // errors that are raised as Rust panics keep their message
+        Err(payload) => {
+            if let Some(err) = payload.downcast_ref::<error::LuaError>() {
+                return Err(err.get_message().to_string());
+            }
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".to_string());
+            Err(format!("panic in Rust function: {}", message))
+        }
+    }
+}
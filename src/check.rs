@@ -0,0 +1,197 @@
+//! Runtime validation of stack handles.
+//!
+//! With the `checked-handles` feature enabled, every Context is given a unique generation
+//! number, and every handle records the stack and call depth it was created at along with the
+//! generation of the innermost Context that was live at the time. Handles verify this record
+//! whenever they are used, and panic if the Context that created them is gone, if they are used
+//! with a different State or call depth, or if they refer to a position above the top of the
+//! stack.
+//!
+//! Without the feature, these types are empty and every check compiles to nothing.
+
+#[cfg(feature = "checked-handles")]
+pub use self::checked::*;
+#[cfg(not(feature = "checked-handles"))]
+pub use self::unchecked::*;
+
+#[cfg(feature = "checked-handles")]
+mod checked {
+    use lua::{State, Index};
+    use types;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Identifies a call level of a single Lua thread.
+    ///
+    /// Stack indices are relative to the running function, so a handle is only meaningful at the
+    /// call depth that it was created at.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    struct StackId {
+        state: usize,
+        depth: usize,
+    }
+
+    impl StackId {
+        fn of(state: &mut State) -> StackId {
+            StackId {
+                state: state.as_ptr() as usize,
+                depth: call_depth(state),
+            }
+        }
+    }
+
+    /// Count the functions that are running in a state, using `lua_getstack`.
+    fn call_depth(state: &mut State) -> usize {
+        fn exists(state: &mut State, level: usize) -> bool {
+            state.get_stack(level as i32).is_some()
+        }
+        if !exists(state, 0) {
+            return 0;
+        }
+        // every level below the depth exists, so search for the first one that does not
+        let (mut low, mut high) = (0, 1);
+        while exists(state, high) {
+            low = high;
+            high *= 2;
+        }
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if exists(state, mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        high
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct LiveContext {
+        stack: StackId,
+        generation: usize,
+    }
+
+    static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+    static LIVE_CONTEXTS: Mutex<Vec<LiveContext>> = Mutex::new(Vec::new());
+
+    fn live_contexts() -> ::std::sync::MutexGuard<'static, Vec<LiveContext>> {
+        // a panic while the lock is held can not leave the list in an inconsistent state
+        LIVE_CONTEXTS.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Records that a Context is live.
+    #[derive(Debug)]
+    pub struct ContextGeneration {
+        generation: usize,
+    }
+
+    impl ContextGeneration {
+        /// Record a new Context for the given state.
+        pub fn new(state: &mut State) -> ContextGeneration {
+            let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+            let stack = StackId::of(state);
+            let mut live = live_contexts();
+            // a Lua error can jump out of functions without dropping their contexts, which are
+            // gone once a context is created further down the same stack
+            live.retain(|context| {
+                context.stack.state != stack.state || context.stack.depth <= stack.depth
+            });
+            live.push(LiveContext {
+                stack,
+                generation,
+            });
+            ContextGeneration {
+                generation,
+            }
+        }
+    }
+
+    impl Drop for ContextGeneration {
+        fn drop(&mut self) {
+            let mut live = live_contexts();
+            if let Some(pos) = live.iter().rposition(|c| c.generation == self.generation) {
+                live.remove(pos);
+            }
+        }
+    }
+
+    /// Records where a handle was created.
+    #[derive(Copy, Clone, Debug)]
+    pub struct HandleTag {
+        origin: Option<(StackId, usize)>,
+    }
+
+    impl HandleTag {
+        /// A tag for a handle created in the innermost live Context of the given state.
+        pub fn current(state: &mut State) -> HandleTag {
+            let id = StackId::of(state);
+            let origin = live_contexts().iter()
+                .rev()
+                .find(|c| c.stack == id)
+                .map(|c| (c.stack, c.generation));
+            HandleTag {
+                origin
+            }
+        }
+
+        /// Check that a handle with this tag can be used with the given state.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the handle belongs to another State or call level, if the Context that
+        /// created the handle has gone out of scope, or if the index is above the top of the
+        /// stack.
+        pub fn verify(&self, state: &mut State, index: Index) {
+            if let Some((stack, generation)) = self.origin {
+                let id = StackId::of(state);
+                if stack.state != id.state {
+                    panic!("handle to stack index {} belongs to a different Lua state", index);
+                }
+                if stack.depth != id.depth {
+                    panic!("handle to stack index {} belongs to a different function call", index);
+                }
+                if !live_contexts().iter().any(|c| c.generation == generation) {
+                    panic!("handle to stack index {} was used after the Context that created it \
+                        went out of scope", index);
+                }
+            }
+            let top = state.get_top();
+            if !types::is_pseudo_index(index) && index > top {
+                panic!("handle to stack index {} is above the top of the stack ({})", index, top);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "checked-handles"))]
+mod unchecked {
+    use lua::{State, Index};
+
+    /// Records that a Context is live.
+    #[derive(Debug)]
+    pub struct ContextGeneration;
+
+    impl ContextGeneration {
+        /// Record a new Context for the given state.
+        #[inline(always)]
+        pub fn new(_: &mut State) -> ContextGeneration {
+            ContextGeneration
+        }
+    }
+
+    /// Records where a handle was created.
+    #[derive(Copy, Clone, Debug)]
+    pub struct HandleTag;
+
+    impl HandleTag {
+        /// A tag for a handle created in the innermost live Context of the given state.
+        #[inline(always)]
+        pub fn current(_: &mut State) -> HandleTag {
+            HandleTag
+        }
+
+        /// Check that a handle with this tag can be used with the given state.
+        #[inline(always)]
+        pub fn verify(&self, _: &mut State, _: Index) {}
+    }
+}
@@ -0,0 +1,595 @@
+//! Builder for exposing Rust types to Lua as classes.
+//!
+//! A `LuaClass` collects methods, metamethods and static functions for a type that implements
+//! `LuaUserData`, and registers them into the metatable that `Context::push_userdata` gives to
+//! values of that type. Arguments and return values are converted with `FromLuaMulti` and
+//! `ToLuaMulti`, and every conversion error names the function that failed.
+//!
+//! # Examples
+//!
+//! ```
+//! # use luaext::lua::State;
+//! # use luaext::context::Context;
+//! use luaext::class::{LuaClass, MetaMethod};
+//! use luaext::userdata::LuaUserData;
+//!
+//! struct Counter {
+//!     count: i64,
+//! }
+//!
+//! impl LuaUserData for Counter {}
+//!
+//! # let mut state = State::new();
+//! # let mut context = Context::new(&mut state);
+//! LuaClass::<Counter>::new("Counter")
+//!     .constructor("new", |_, (count,): (i64,)| Ok(Counter { count }))
+//!     .method("get", |_, this, ()| Ok((this.count,)))
+//!     .method_mut("add", |_, this, (n,): (i64,)| {
+//!         this.count += n;
+//!         Ok(())
+//!     })
+//!     .meta_method(MetaMethod::ToString, |_, this, ()| Ok((format!("Counter({})", this.count),)))
+//!     .register(&mut context);
+//!
+//! context.do_string("counter = Counter.new(2); counter:add(3); count = counter:get()").unwrap();
+//! let count = context.push_global("count").get_value::<i64>(&mut context);
+//! assert_eq!(count, Some(5));
+//! ```
+
+use lua::{Index, Type, ToLua, FromLua};
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::ops::{Add, Sub, Mul, Neg};
+use std::fmt;
+use context::Context;
+use callback::{self, RustFunction};
+use multi::{ToLuaMulti, FromLuaMulti, Variadic};
+use types::{self, LuaGeneric, LuaStackable, LuaTable, LuaUserdata};
+use userdata::{self, LuaUserData, ClassInfo, Upcast, CLASS_FIELD};
+use error;
+
+/// Metamethods that can be given to a class.
+///
+/// `__index`, `__newindex` and `__gc` are not included, since they are set by the class itself.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MetaMethod {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    BNot,
+    Shl,
+    Shr,
+    Concat,
+    Len,
+    Eq,
+    Lt,
+    Le,
+    Call,
+    ToString,
+}
+
+impl MetaMethod {
+    /// Get the name of this metamethod's field in a metatable.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::IDiv => "__idiv",
+            MetaMethod::BAnd => "__band",
+            MetaMethod::BOr => "__bor",
+            MetaMethod::BXor => "__bxor",
+            MetaMethod::BNot => "__bnot",
+            MetaMethod::Shl => "__shl",
+            MetaMethod::Shr => "__shr",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::Call => "__call",
+            MetaMethod::ToString => "__tostring",
+        }
+    }
+}
+
+/// Trait for types that add their own methods to a class, usually implemented with the
+/// `#[lua_methods]` attribute of the `lua53-ext-derive` crate.
+pub trait LuaMethods: LuaUserData + Sized {
+    /// Add this type's methods, properties and constructors to a class.
+    fn add_methods(class: LuaClass<Self>) -> LuaClass<Self>;
+}
+
+/// Register the class for the type T, named after `LuaUserData::type_name`, with the methods
+/// added by `LuaMethods`.
+pub fn register<T: LuaMethods>(context: &mut Context) -> types::LuaTable {
+    LuaClass::<T>::new(T::type_name()).with_methods().register(context)
+}
+
+/// A builder that registers a Rust type as a Lua class.
+///
+/// Methods are called on values of the type, as in `value:method(...)`. Static functions and
+/// constructors are stored in a class table, which `register` sets as a global, so that
+/// a constructor named `new` is called as `ClassName.new(...)`.
+///
+/// Properties declared with `getter` and `setter` are read and assigned as fields, as in
+/// `value.x = value.x + 1`. Any other field is looked up in the methods.
+pub struct LuaClass<T: LuaUserData> {
+    name: String,
+    methods: Vec<(String, RustFunction)>,
+    metamethods: Vec<(MetaMethod, RustFunction)>,
+    functions: Vec<(String, RustFunction)>,
+    getters: Vec<(String, RustFunction)>,
+    setters: Vec<(String, RustFunction)>,
+    protect: bool,
+    parent: Option<Parent>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: LuaUserData> LuaClass<T> {
+    /// Create a new class with the given name.
+    pub fn new(name: &str) -> LuaClass<T> {
+        LuaClass {
+            name: name.to_string(),
+            methods: Vec::new(),
+            metamethods: Vec::new(),
+            functions: Vec::new(),
+            getters: Vec::new(),
+            setters: Vec::new(),
+            protect: true,
+            parent: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Add the methods of a type that implements `LuaMethods`.
+    pub fn with_methods(self) -> Self where T: LuaMethods {
+        T::add_methods(self)
+    }
+
+    /// Add a method that borrows the value it is called on.
+    pub fn method<A, R, F>(mut self, name: &str, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, &T, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}:{}", self.name, name);
+        self.methods.push((name.to_string(), method(full_name, func)));
+        self
+    }
+
+    /// Add a method that mutably borrows the value it is called on.
+    pub fn method_mut<A, R, F>(mut self, name: &str, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, &mut T, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}:{}", self.name, name);
+        self.methods.push((name.to_string(), method_mut(full_name, func)));
+        self
+    }
+
+    /// Add a property that can be read from Lua, as in `value.name`.
+    ///
+    /// A property without a setter is read-only; assigning to it raises an error.
+    pub fn getter<R, F>(mut self, name: &str, func: F) -> Self
+            where R: ToLua, F: Fn(&mut Context, &T) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, name);
+        self.getters.push((name.to_string(), getter(full_name, func)));
+        self
+    }
+
+    /// Add a property that can be assigned from Lua, as in `value.name = x`.
+    pub fn setter<V, F>(mut self, name: &str, func: F) -> Self
+            where V: FromLua,
+                  F: Fn(&mut Context, &mut T, V) -> error::Result<()> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, name);
+        self.setters.push((name.to_string(), setter(full_name, func)));
+        self
+    }
+
+    /// Add a metamethod that borrows its first operand, which must be a value of this class.
+    pub fn meta_method<A, R, F>(mut self, meta: MetaMethod, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, &T, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, meta.name());
+        self.metamethods.push((meta, method(full_name, func)));
+        self
+    }
+
+    /// Add a metamethod that mutably borrows its first operand, which must be a value of this
+    /// class.
+    pub fn meta_method_mut<A, R, F>(mut self, meta: MetaMethod, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, &mut T, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, meta.name());
+        self.metamethods.push((meta, method_mut(full_name, func)));
+        self
+    }
+
+    /// Add a metamethod that takes all of its operands as arguments.
+    ///
+    /// Binary operators are called when either operand is a value of this class, as in
+    /// `1 + value`, so their arguments should be converted with this method.
+    pub fn meta_function<A, R, F>(mut self, meta: MetaMethod, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, meta.name());
+        self.metamethods.push((meta, function(full_name, func)));
+        self
+    }
+
+    /// Add a static function to the class table.
+    pub fn function<A, R, F>(mut self, name: &str, func: F) -> Self
+            where A: FromLuaMulti, R: ToLuaMulti,
+                  F: Fn(&mut Context, A) -> error::Result<R> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, name);
+        self.functions.push((name.to_string(), function(full_name, func)));
+        self
+    }
+
+    /// Add a static function to the class table that returns a new value of this class.
+    pub fn constructor<A, F>(mut self, name: &str, func: F) -> Self
+            where A: FromLuaMulti, F: Fn(&mut Context, A) -> error::Result<T> + Send + 'static {
+        let full_name = format!("{}.{}", self.name, name);
+        self.functions.push((name.to_string(), Box::new(move |mut context| {
+            let args = get_args(&mut context, &full_name, 1)?;
+            let value = func(&mut context, args)?;
+            let userdata = context.push_userdata(value);
+            Ok(context.return_values((userdata,)))
+        })));
+        self
+    }
+
+    /// Add a `__tostring` metamethod that formats values with `Display`.
+    pub fn derive_display(self) -> Self where T: fmt::Display {
+        self.meta_method(MetaMethod::ToString, |_, this, ()| Ok((this.to_string(),)))
+    }
+
+    /// Add an `__eq` metamethod that compares values with `PartialEq`.
+    ///
+    /// A value is never equal to a userdata of another type. Lua calls `__eq` with either
+    /// operand first, so neither one is assumed to be a value of this class.
+    pub fn derive_eq(self) -> Self where T: PartialEq {
+        self.meta_function(MetaMethod::Eq, |context, (a, b): (LuaGeneric, LuaGeneric)| {
+            let (a, b) = match (a.get_value::<LuaUserdata>(context),
+                    b.get_value::<LuaUserdata>(context)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Ok((false,)),
+            };
+            let equal = match (a.try_borrow::<T>(context), b.try_borrow::<T>(context)) {
+                (Ok(a), Ok(b)) => *a == *b,
+                _ => false,
+            };
+            Ok((equal,))
+        })
+    }
+
+    /// Add `__lt` and `__le` metamethods that compare values with `PartialOrd`.
+    pub fn derive_ord(self) -> Self where T: PartialOrd {
+        self.comparison(MetaMethod::Lt, |a, b| a < b)
+            .comparison(MetaMethod::Le, |a, b| a <= b)
+    }
+
+    /// Add an `__add` metamethod that adds two values of this class with `Add`.
+    pub fn derive_add(self) -> Self where T: Clone + Add<Output = T> {
+        self.binary_op(MetaMethod::Add, |a, b| a + b)
+    }
+
+    /// Add a `__sub` metamethod that subtracts two values of this class with `Sub`.
+    pub fn derive_sub(self) -> Self where T: Clone + Sub<Output = T> {
+        self.binary_op(MetaMethod::Sub, |a, b| a - b)
+    }
+
+    /// Add a `__mul` metamethod that multiplies two values of this class with `Mul`.
+    pub fn derive_mul(self) -> Self where T: Clone + Mul<Output = T> {
+        self.binary_op(MetaMethod::Mul, |a, b| a * b)
+    }
+
+    /// Add an `__unm` metamethod that negates values with `Neg`.
+    pub fn derive_neg(self) -> Self where T: Clone + Neg<Output = T> {
+        // Lua passes the operand twice to __unm
+        self.meta_method(MetaMethod::Unm, |context, this, _: Variadic<LuaGeneric>| {
+            Ok((context.push_userdata(-this.clone()),))
+        })
+    }
+
+    /// Add a `clone` method that copies values with `Clone`.
+    pub fn derive_clone(self) -> Self where T: Clone {
+        self.method("clone", |context, this, ()| Ok((context.push_userdata(this.clone()),)))
+    }
+
+    fn comparison<F>(self, meta: MetaMethod, compare: F) -> Self
+            where F: Fn(&T, &T) -> bool + Send + 'static {
+        let name = format!("{}.{}", self.name, meta.name());
+        self.meta_function(meta, move |context, (a, b): (LuaGeneric, LuaGeneric)| {
+            let (a, b) = (operand::<T>(context, &a, &name)?, operand::<T>(context, &b, &name)?);
+            let a = a.try_borrow::<T>(context).or_else(|err| named(&name, err))?;
+            let b = b.try_borrow::<T>(context).or_else(|err| named(&name, err))?;
+            Ok((compare(&a, &b),))
+        })
+    }
+
+    fn binary_op<F>(self, meta: MetaMethod, op: F) -> Self
+            where T: Clone, F: Fn(T, T) -> T + Send + 'static {
+        let name = format!("{}.{}", self.name, meta.name());
+        self.meta_function(meta, move |context, (a, b): (LuaGeneric, LuaGeneric)| {
+            let (a, b) = (operand::<T>(context, &a, &name)?, operand::<T>(context, &b, &name)?);
+            let a = a.try_borrow::<T>(context).or_else(|err| named(&name, err))?.clone();
+            let b = b.try_borrow::<T>(context).or_else(|err| named(&name, err))?.clone();
+            Ok((context.push_userdata(op(a, b)),))
+        })
+    }
+
+    /// Make this class extend the class of the type P.
+    ///
+    /// Methods that are not found in this class are looked up in the methods of P, and values
+    /// of this class can be borrowed as a P with `LuaUserdata::borrow`, using the given
+    /// functions to get the P that each value contains. Since the methods of P borrow their
+    /// receiver as a P, they work on values of this class too. Parents of P are also parents of
+    /// this class, as long as P is registered first.
+    ///
+    /// Properties and metamethods are not inherited.
+    pub fn extends<P, F, G>(mut self, upcast: F, upcast_mut: G) -> Self
+            where P: LuaUserData, F: Fn(&T) -> &P + Send + 'static,
+                  G: Fn(&mut T) -> &mut P + Send + 'static {
+        self.parent = Some(Parent {
+            type_id: TypeId::of::<P>(),
+            metatable_key: userdata::metatable_key::<P>(),
+            upcast: Upcast::new(upcast, upcast_mut),
+        });
+        self
+    }
+
+    /// Set whether the metatable is hidden from Lua.
+    ///
+    /// A protected metatable can not be retrieved with `getmetatable`, which returns the class
+    /// name instead. Metatables are protected by default.
+    pub fn protect_metatable(mut self, protect: bool) -> Self {
+        self.protect = protect;
+        self
+    }
+
+    /// Register this class into its type's metatable, and set its class table as a global.
+    ///
+    /// Values of this type that already exist also get the new methods, since they share their
+    /// metatable. Returns the class table.
+    pub fn register(self, context: &mut Context) -> types::LuaTable {
+        let metatable = context.userdata_metatable::<T>();
+        let methods = methods_table(context, &userdata::metatable_key::<T>());
+        for (name, func) in self.methods {
+            let func = callback::push_closure(context, func);
+            methods.set_raw(context, &name, &func);
+        }
+        if self.getters.is_empty() && self.setters.is_empty() {
+            metatable.set_raw(context, &"__index", &methods);
+            metatable.set_raw(context, &"__newindex", &None::<bool>);
+        } else {
+            let (index, newindex) = property_functions(&self.name, self.getters, self.setters);
+            context.reserve(1);
+            methods.to_lua(context.get_state());
+            let index = callback::push_closure_with_upvalues(context, index, 1);
+            metatable.set_raw(context, &"__index", &index);
+            let newindex = callback::push_closure(context, newindex);
+            metatable.set_raw(context, &"__newindex", &newindex);
+        }
+        for (meta, func) in self.metamethods {
+            let func = callback::push_closure(context, func);
+            metatable.set_raw(context, &meta.name(), &func);
+        }
+        if self.protect {
+            metatable.set_raw(context, &"__metatable", &self.name);
+        } else {
+            metatable.set_raw(context, &"__metatable", &None::<bool>);
+        }
+        if let Some(parent) = self.parent {
+            let upcasts = parent.upcasts(context);
+            let info = context.push_userdata(ClassInfo::new::<T>(upcasts));
+            metatable.set_raw(context, &CLASS_FIELD, &info);
+            let parent_methods = methods_table(context, &parent.metatable_key);
+            let methods_metatable = context.push_table();
+            methods_metatable.set_raw(context, &"__index", &parent_methods);
+            methods.set_metatable(context, &methods_metatable);
+        }
+        let class = context.push_table();
+        for (name, func) in self.functions {
+            let func = callback::push_closure(context, func);
+            class.set_raw(context, &name, &func);
+        }
+        context.set_global(&self.name, &class);
+        class
+    }
+}
+
+/// The class that a class extends.
+struct Parent {
+    type_id: TypeId,
+    metatable_key: String,
+    upcast: Upcast,
+}
+
+impl Parent {
+    /// Get the upcasts from the class to this parent and every parent of this parent.
+    fn upcasts(&self, context: &mut Context) -> HashMap<TypeId, Upcast> {
+        let mut upcasts = HashMap::new();
+        if let Some(metatable) = context.metatable_get(&self.metatable_key) {
+            let info = metatable.get_raw(context, &CLASS_FIELD);
+            let state = context.get_state();
+            if let Some(info) = unsafe { userdata::get_cell::<ClassInfo>(state, info.get_pos()) } {
+                let info = info.try_borrow().expect("class info is never borrowed mutably");
+                for (type_id, upcast) in &info.upcasts {
+                    upcasts.insert(*type_id, self.upcast.then(upcast));
+                }
+            }
+        }
+        upcasts.insert(self.type_id, self.upcast.clone());
+        upcasts
+    }
+}
+
+/// Get the methods table of the class with the given metatable key, creating it if needed.
+///
+/// Methods tables are kept in the registry, so that a class can refer to the methods of its
+/// parent before the parent is registered.
+fn methods_table(context: &mut Context, metatable_key: &str) -> LuaTable {
+    let key = format!("{}.methods", metatable_key);
+    if let Some(methods) = context.get_from_registry(&key).get_value::<LuaTable>(context) {
+        return methods;
+    }
+    let methods = context.push_table();
+    context.set_in_registry(&key, &methods);
+    methods
+}
+
+/// Create the `__index` and `__newindex` functions of a class with properties.
+///
+/// `__index` falls back to the methods table, which must be its second upvalue.
+fn property_functions(class: &str, getters: Vec<(String, RustFunction)>,
+        setters: Vec<(String, RustFunction)>) -> (RustFunction, RustFunction) {
+    let readonly: HashSet<String> = getters.iter()
+        .map(|(name, _)| name.clone())
+        .filter(|name| !setters.iter().any(|(setter, _)| setter == name))
+        .collect();
+    let getters: HashMap<String, RustFunction> = getters.into_iter().collect();
+    let setters: HashMap<String, RustFunction> = setters.into_iter().collect();
+    let index = Box::new(move |mut context: Context| {
+        if let Some(getter) = property_name(&mut context).and_then(|name| getters.get(&name)) {
+            return getter(context);
+        }
+        let methods: LuaTable = context.get_upvalue(2).and_then(|v| v.get_value(&mut context))
+            .expect("the methods table is not an upvalue of __index");
+        let key = context.get_arg(2).expect("__index was called without a key");
+        let value = methods.get(&mut context, &key);
+        Ok(context.return_values((value,)))
+    });
+    let class = class.to_string();
+    let newindex = Box::new(move |mut context: Context| {
+        let name = property_name(&mut context);
+        if let Some(setter) = name.as_ref().and_then(|name| setters.get(name)) {
+            return setter(context);
+        }
+        let message = match name {
+            Some(ref name) if readonly.contains(name) => {
+                format!("{}.{} is read-only", class, name)
+            },
+            Some(ref name) => format!("{} has no property '{}'", class, name),
+            None => format!("{} has no property of that name", class),
+        };
+        error::new_luaresult_err(error::LuaErrorType::RuntimeError, message)
+    });
+    (index, newindex)
+}
+
+/// Get the key given to `__index` or `__newindex` if it is a string.
+fn property_name(context: &mut Context) -> Option<String> {
+    let state = context.get_state();
+    match state.type_of(2) {
+        Some(Type::String) => state.to_str_in_place(2).map(|s| s.to_string()),
+        _ => None,
+    }
+}
+
+/// Get an operand of a binary metamethod, which must be a value of the class T.
+fn operand<T: LuaUserData>(context: &mut Context, value: &LuaGeneric, name: &str)
+        -> error::Result<LuaUserdata> {
+    match value.get_value::<LuaUserdata>(context) {
+        Some(userdata) if userdata.is::<T>(context) => error::new_luaresult_ok(userdata),
+        _ => {
+            let typename = context.get_state().typename_at(value.get_pos());
+            error::new_luaresult_err(error::LuaErrorType::TypeError,
+                format!("{}: expected a {}, got a {} value", name, T::type_name(), typename))
+        },
+    }
+}
+
+/// Prefix an error's message with the name of the function it occured in.
+fn named<V>(name: &str, err: error::LuaError) -> error::Result<V> {
+    error::new_luaresult_err(err.get_type(), format!("{}: {}", name, err.get_message()))
+}
+
+/// Convert the arguments of a function, starting at the argument `first`.
+fn get_args<A: FromLuaMulti>(context: &mut Context, name: &str, first: Index)
+        -> error::Result<A> {
+    let count = context.arg_count() - first + 1;
+    A::from_lua_multi(context.get_state(), first, count).or_else(|err| named(name, err))
+}
+
+/// Get the value a method was called on.
+fn get_self<T: LuaUserData>(context: &mut Context, name: &str) -> error::Result<LuaUserdata> {
+    match context.get_arg_typed::<LuaUserdata>(1) {
+        Some(userdata) => error::new_luaresult_ok(userdata),
+        None => error::new_luaresult_err(error::LuaErrorType::TypeError,
+            format!("{}: expected a {} as the first argument", name, T::type_name())),
+    }
+}
+
+fn method<T, A, R, F>(name: String, func: F) -> RustFunction
+        where T: LuaUserData, A: FromLuaMulti, R: ToLuaMulti,
+              F: Fn(&mut Context, &T, A) -> error::Result<R> + Send + 'static {
+    Box::new(move |mut context| {
+        let this = get_self::<T>(&mut context, &name)?;
+        let value = this.try_borrow::<T>(&mut context).or_else(|err| named(&name, err))?;
+        let args = get_args(&mut context, &name, 2)?;
+        let ret = func(&mut context, &value, args)?;
+        drop(value);
+        Ok(context.return_values(ret))
+    })
+}
+
+fn method_mut<T, A, R, F>(name: String, func: F) -> RustFunction
+        where T: LuaUserData, A: FromLuaMulti, R: ToLuaMulti,
+              F: Fn(&mut Context, &mut T, A) -> error::Result<R> + Send + 'static {
+    Box::new(move |mut context| {
+        let this = get_self::<T>(&mut context, &name)?;
+        let mut value = this.try_borrow_mut::<T>(&mut context).or_else(|err| named(&name, err))?;
+        let args = get_args(&mut context, &name, 2)?;
+        let ret = func(&mut context, &mut value, args)?;
+        drop(value);
+        Ok(context.return_values(ret))
+    })
+}
+
+fn getter<T, R, F>(name: String, func: F) -> RustFunction
+        where T: LuaUserData, R: ToLua,
+              F: Fn(&mut Context, &T) -> error::Result<R> + Send + 'static {
+    Box::new(move |mut context| {
+        let this = get_self::<T>(&mut context, &name)?;
+        let value = this.try_borrow::<T>(&mut context).or_else(|err| named(&name, err))?;
+        let ret = func(&mut context, &value)?;
+        drop(value);
+        Ok(context.return_values((ret,)))
+    })
+}
+
+fn setter<T, V, F>(name: String, func: F) -> RustFunction
+        where T: LuaUserData, V: FromLua,
+              F: Fn(&mut Context, &mut T, V) -> error::Result<()> + Send + 'static {
+    Box::new(move |mut context| {
+        let this = get_self::<T>(&mut context, &name)?;
+        let mut value = this.try_borrow_mut::<T>(&mut context).or_else(|err| named(&name, err))?;
+        let (new_value,) = get_args::<(V,)>(&mut context, &name, 3)?;
+        func(&mut context, &mut value, new_value)?;
+        drop(value);
+        Ok(0)
+    })
+}
+
+fn function<A, R, F>(name: String, func: F) -> RustFunction
+        where A: FromLuaMulti, R: ToLuaMulti,
+              F: Fn(&mut Context, A) -> error::Result<R> + Send + 'static {
+    Box::new(move |mut context| {
+        let args = get_args(&mut context, &name, 1)?;
+        let ret = func(&mut context, args)?;
+        Ok(context.return_values(ret))
+    })
+}
+
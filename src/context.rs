@@ -1,15 +1,32 @@
-use lua::{State, Index, ToLua, FromLua, Function, REGISTRYINDEX};
+use lua::{State, Index, ToLua, FromLua, Function, Type, REGISTRYINDEX};
+use lua::libc::c_void;
 use types;
 use std::ptr;
+use std::thread;
+use std::vec;
+use std::cmp;
+use std::fmt::Write;
 use error;
+use check::ContextGeneration;
+use libs::{self, LuaLibs};
+use multi::{ToLuaMulti, FromLuaMulti};
+use userdata::{self, LuaUserData};
+use callback;
+use protect;
 
 /// A wrapper around a Lua State.
 ///
 /// Contains its own section of a Lua Stack; when the context goes out of scope, any value pushed
 /// using this context is popped.
+///
+/// Every push method makes sure the stack has room for the new values first, and panics if the
+/// stack can not grow any further. Each push method has a `try_` variant that returns an error
+/// instead.
 pub struct Context<'a> {
     state: &'a mut State,
     target_pos: Index,
+    // marks this context as live for as long as it exists; see the check module
+    _generation: ContextGeneration,
 }
 
 /// __gc metamethod used to clean up Rust types that implements the Drop trait.
@@ -27,11 +44,13 @@ pub fn userdata_drop<T>(state: &mut State) -> i32 {
 
 impl<'a> Context<'a> {
     /// Creates a new Context using an existing state.
-    pub fn new(state: &mut State) -> Context {
+    pub fn new(state: &mut State) -> Context<'_> {
         let pos = state.get_top();
+        let generation = ContextGeneration::new(state);
         Context {
-            state: state,
+            state,
             target_pos: pos,
+            _generation: generation,
         }
     }
 
@@ -40,135 +59,368 @@ impl<'a> Context<'a> {
         self.state
     }
 
+    /// Get a handle to the value at the given index, recording where the handle was created.
+    pub(crate) fn handle<T: FromLua>(&mut self, i: Index) -> T {
+        T::from_lua(self.state, i).expect("value on the stack does not have the expected type")
+    }
+
+    /// Make sure there is room for at least `n` more values on the stack.
+    ///
+    /// Every push method already does this, so this only needs to be called before pushing
+    /// values using the contained State directly.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the stack can not grow to fit `n` more values.
+    pub fn reserve(&mut self, n: i32) {
+        if let Err(err) = self.try_reserve(n) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Make sure there is room for at least `n` more values on the stack.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stack can not grow to fit `n` more values, either because the
+    /// stack would exceed Lua's maximum stack size or because memory could not be allocated.
+    pub fn try_reserve(&mut self, n: i32) -> error::Result<()> {
+        if n <= 0 || self.state.check_stack(n) {
+            error::new_luaresult_ok(())
+        } else {
+            error::new_luaresult_err(error::LuaErrorType::StackError,
+                format!("stack overflow (can not grow the stack by {} values)", n))
+        }
+    }
+
+    /// Open the given standard libraries.
+    pub fn open_libs(&mut self, libs: LuaLibs) {
+        libs::open_libs(self.state, libs);
+    }
+
     /// Push a floating point number onto the stack.
     pub fn push_number(&mut self, value: f64) -> types::LuaNumber {
+        self.reserve(1);
         self.state.push_number(value);
         let i = self.state.get_top();
-        types::LuaNumber::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_number`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_number(&mut self, value: f64) -> error::Result<types::LuaNumber> {
+        self.try_reserve(1)?;
+        Ok(self.push_number(value))
     }
 
     /// Push a string onto the stack.
     pub fn push_string(&mut self, value: &str) -> types::LuaString {
+        self.reserve(1);
         self.state.push_string(value);
         let i = self.state.get_top();
-        types::LuaString::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_string`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_string(&mut self, value: &str) -> error::Result<types::LuaString> {
+        self.try_reserve(1)?;
+        Ok(self.push_string(value))
     }
 
     /// Create a new table and push it into the stack.
     pub fn push_table(&mut self) -> types::LuaTable {
+        self.reserve(1);
         self.state.new_table();
         let i = self.state.get_top();
-        types::LuaTable::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_table`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_table(&mut self) -> error::Result<types::LuaTable> {
+        self.try_reserve(1)?;
+        Ok(self.push_table())
     }
 
     /// Push a boolean value onto the stack.
     pub fn push_bool(&mut self, value: bool) -> types::LuaBool {
+        self.reserve(1);
         self.state.push_bool(value);
         let i = self.state.get_top();
-        types::LuaBool::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_bool`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_bool(&mut self, value: bool) -> error::Result<types::LuaBool> {
+        self.try_reserve(1)?;
+        Ok(self.push_bool(value))
     }
 
     /// Push a C function onto the stack.
     pub fn push_function(&mut self, func: Function) -> types::LuaFunction {
+        self.reserve(1);
         self.state.push_fn(func);
         let i = self.state.get_top();
-        types::LuaFunction::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_function`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_function(&mut self, func: Function) -> error::Result<types::LuaFunction> {
+        self.try_reserve(1)?;
+        Ok(self.push_function(func))
+    }
+
+    /// Push a Rust closure onto the stack as a Lua function.
+    ///
+    /// The closure is given a new Context for the function's arguments, and returns the number
+    /// of values it returns, usually with `return_values`. Errors returned by the closure, and
+    /// panics, are raised as Lua errors.
+    ///
+    /// The closure must be `Send`, since the State that holds it can be moved to another thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// # let mut state = State::new();
+    /// # let mut context = Context::new(&mut state);
+    /// let offset = 10;
+    /// let add = context.push_closure(move |mut context| {
+    ///     let (n,): (i64,) = context.get_args()?;
+    ///     Ok(context.return_values((n + offset,)))
+    /// });
+    /// let result: (i64,) = add.pcall_typed(&mut context, (5,)).unwrap();
+    /// assert_eq!(result, (15,));
+    /// ```
+    pub fn push_closure<F>(&mut self, func: F) -> types::LuaFunction
+            where F: Fn(Context) -> error::Result<Index> + Send + 'static {
+        callback::push_closure(self, Box::new(func))
+    }
+
+    /// Same as `push_closure`, but returns an error if the stack can not grow to fit the new
+    /// value instead of panicking.
+    pub fn try_push_closure<F>(&mut self, func: F) -> error::Result<types::LuaFunction>
+            where F: Fn(Context) -> error::Result<Index> + Send + 'static {
+        self.try_reserve(2)?;
+        Ok(self.push_closure(func))
     }
 
     /// Push an integer onto the stack.
     pub fn push_integer(&mut self, value: i64) -> types::LuaInteger {
+        self.reserve(1);
         self.state.push_integer(value);
         let i = self.state.get_top();
-        types::LuaInteger::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_integer`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_integer(&mut self, value: i64) -> error::Result<types::LuaInteger> {
+        self.try_reserve(1)?;
+        Ok(self.push_integer(value))
     }
 
     /// Push a nil value onto the stack.
     pub fn push_nil(&mut self) -> types::LuaNil {
+        self.reserve(1);
         self.state.push_nil();
         let i = self.state.get_top();
-        types::LuaNil::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_nil`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_nil(&mut self) -> error::Result<types::LuaNil> {
+        self.try_reserve(1)?;
+        Ok(self.push_nil())
+    }
+
+    /// Push a pointer onto the stack as a light userdata.
+    ///
+    /// Lua does not manage the memory behind the pointer, and light userdata have no metatable
+    /// of their own, so the pointer is only ever compared or handed back to Rust.
+    pub fn push_light_userdata<T>(&mut self, ptr: *mut T) -> types::LuaLightUserdata {
+        self.reserve(1);
+        unsafe { self.state.push_light_userdata(ptr as *mut c_void) };
+        let i = self.state.get_top();
+        self.handle(i)
+    }
+
+    /// Same as `push_light_userdata`, but returns an error if the stack can not grow to fit the
+    /// new value instead of panicking.
+    pub fn try_push_light_userdata<T>(&mut self, ptr: *mut T)
+            -> error::Result<types::LuaLightUserdata> {
+        self.try_reserve(1)?;
+        Ok(self.push_light_userdata(ptr))
     }
 
     /// Push a user-defined value onto the stack.
-    pub fn push_userdata<T>(&mut self, value: T) -> types::LuaUserdata {
-        unsafe { ptr::write(self.state.new_userdata_typed(), value); }
+    ///
+    /// The userdata is given the metatable for the type T, which records its type so that it can
+    /// be borrowed safely with `LuaUserdata::borrow`, and drops the value when Lua collects it.
+    /// The metatable is protected, so `getmetatable` returns `T::type_name()` instead.
+    pub fn push_userdata<T: LuaUserData>(&mut self, value: T) -> types::LuaUserdata {
+        self.reserve(2);
+        let cell = userdata::UserdataCell::new(value);
+        unsafe { ptr::write(userdata::new_userdata(self.state), cell); }
+        userdata::push_metatable::<T>(self);
+        self.state.set_metatable(-2);
         let i = self.state.get_top();
-        types::LuaUserdata::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_userdata<T>`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_userdata<T: LuaUserData>(&mut self, value: T)
+            -> error::Result<types::LuaUserdata> {
+        self.try_reserve(2)?;
+        Ok(self.push_userdata(value))
     }
 
     /// Push a user-defined value onto the stack, and give it the metatable named 'name.'
     pub fn push_userdata_named<T>(&mut self, value: T, name: &str) -> types::LuaUserdata {
-        let entity_object = self.push_userdata(value);
-        let entity_object_meta = self.metatable_get(&name).unwrap();
+        self.reserve(2);
+        unsafe { ptr::write(userdata::new_userdata(self.state), value); }
+        let i = self.state.get_top();
+        let entity_object: types::LuaUserdata = self.handle(i);
+        let entity_object_meta = self.metatable_get(name).unwrap();
         entity_object.set_metatable(self, &entity_object_meta);
         entity_object
     }
 
+    /// Same as `push_userdata_named`, but returns an error if the stack can not grow to fit the
+    /// new value instead of panicking.
+    pub fn try_push_userdata_named<T>(&mut self, value: T, name: &str)
+            -> error::Result<types::LuaUserdata> {
+        self.try_reserve(2)?;
+        Ok(self.push_userdata_named(value, name))
+    }
+
     /// Push a Lua thread onto the stack
     ///
     /// You will need to do stuff with the Lua thread in a separate thread if you want two Lua
     /// threads to run concurrently, Lua doesn't do any actual multithreading itself.
     pub fn push_thread(&mut self) -> types::LuaThread {
+        self.reserve(1);
         self.state.new_thread();
         let i = self.state.get_top();
-        types::LuaThread::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_thread`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_thread(&mut self) -> error::Result<types::LuaThread> {
+        self.try_reserve(1)?;
+        Ok(self.push_thread())
     }
 
     /// Create a library using an array of Functions, and push the library table onto the stack.,
     pub fn create_lib(&mut self, lib: &[(&str, Function)]) -> types::LuaTable {
+        self.reserve(1);
         self.state.new_lib(lib);
         let i = self.state.get_top();
-        types::LuaTable::new(i)
+        self.handle(i)
     }
 
     /// Push a global value onto the stack.
     pub fn push_global(&mut self, key: &str) -> types::LuaGeneric {
-        self.state.get_global(key);
+        self.reserve(3);
+        protect::get_global(self.state, key);
         let i = self.state.get_top();
-        types::LuaGeneric::new(i)
+        self.handle(i)
+    }
+
+    /// Same as `push_global`, but returns an error if the stack can not grow to fit the new value
+    /// instead of panicking.
+    pub fn try_push_global(&mut self, key: &str) -> error::Result<types::LuaGeneric> {
+        self.try_reserve(3)?;
+        Ok(self.push_global(key))
     }
 
     /// Set a value in the global Lua namespace.
-    pub fn set_global(&mut self, key: &str, value: &ToLua) {
+    ///
+    /// # Panics
+    ///
+    /// Panics with a `LuaError` payload if the global table has a `__newindex` metamethod that
+    /// raises an error.
+    pub fn set_global(&mut self, key: &str, value: &dyn ToLua) {
+        self.reserve(4);
         value.to_lua(self.state);
-        self.state.set_global(key);
+        protect::set_global(self.state, key);
     }
 
     /// Get a value from the Lua registry.
-    pub fn get_from_registry(&mut self, key: &ToLua) -> types::LuaGeneric {
+    pub fn get_from_registry(&mut self, key: &dyn ToLua) -> types::LuaGeneric {
+        self.reserve(1);
         key.to_lua(self.state);
-        self.state.get_table(REGISTRYINDEX);
+        self.state.raw_get(REGISTRYINDEX);
         let i = self.state.get_top();
-        types::LuaGeneric::new(i)
+        self.handle(i)
     }
 
     /// Get a value from the Lua registry using a type.
-    pub fn get_from_registry_typed<T: FromLua>(&mut self, key: &ToLua) -> Option<T> {
+    pub fn get_from_registry_typed<T: FromLua>(&mut self, key: &dyn ToLua) -> Option<T> {
+        self.reserve(1);
         key.to_lua(self.state);
-        self.state.get_table(REGISTRYINDEX);
+        self.state.raw_get(REGISTRYINDEX);
         let i = self.state.get_top();
-        T::from_lua(&mut self.state, i)
+        T::from_lua(self.state, i)
     }
 
     /// Set a value in the Lua registry.
-    pub fn set_in_registry(&mut self, key: &ToLua, value: &ToLua) {
+    pub fn set_in_registry(&mut self, key: &dyn ToLua, value: &dyn ToLua) {
+        self.reserve(4);
         key.to_lua(self.state);
         value.to_lua(self.state);
-        self.state.set_table(REGISTRYINDEX);
+        protect::set_table_at(self.state, REGISTRYINDEX);
+    }
+
+    /// Convert a relative (negative) index into an absolute index.
+    ///
+    /// Absolute indices and pseudo-indices are returned unchanged.
+    pub fn abs_index(&mut self, index: Index) -> Index {
+        self.state.abs_index(index)
     }
 
     /// Get an argument from this context.
+    ///
+    /// Negative indices count down from the top of the stack, but the returned value always
+    /// refers to the same position, even if more values are pushed.
     pub fn get_arg(&mut self, arg: Index) -> Option<types::LuaGeneric> {
         if self.state.is_none(arg) {
             None
         } else {
-            Some(types::LuaGeneric::new(arg))
+            Some(self.handle(arg))
+        }
+    }
+
+    /// Get an upvalue of the running function.
+    ///
+    /// Upvalues are numbered starting from 1. Returns None if the running function does not
+    /// have the given upvalue.
+    pub fn get_upvalue(&mut self, n: Index) -> Option<types::LuaGeneric> {
+        let i = types::upvalue_index(n);
+        if self.state.is_none(i) {
+            None
+        } else {
+            Some(self.handle(i))
         }
     }
 
+    /// Get the Lua registry table.
+    ///
+    /// The returned table refers to the registry using a pseudo-index, so nothing is pushed.
+    pub fn get_registry(&mut self) -> types::LuaTable {
+        self.handle(REGISTRYINDEX)
+    }
+
     /// Get an argument from this context.
     pub fn get_arg_typed<T: FromLua>(&mut self, arg: Index) -> Option<T> {
-        T::from_lua(&mut self.state, arg)
+        T::from_lua(self.state, arg)
     }
 
     /// Get an argument from this context.
@@ -178,23 +430,89 @@ impl<'a> Context<'a> {
         if self.state.is_none_or_nil(arg) {
             Some(value)
         } else {
-            T::from_lua(&mut self.state, arg)
+            T::from_lua(self.state, arg)
         }
     }
 
+    /// Get the number of arguments passed to this context.
+    ///
+    /// This is the number of values that were on the stack when this context was created, which
+    /// for a context created at the start of a Rust function called from Lua is the number of
+    /// arguments the function was called with.
+    pub fn arg_count(&self) -> Index {
+        self.target_pos
+    }
+
+    /// Get every argument passed to this context, in order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// fn count_strings(state: &mut State) -> i32 {
+    ///     let mut context = Context::new(state);
+    ///     let count = context.args()
+    ///         .filter(|arg| arg.type_of(&mut context) == luaext::lua::Type::String)
+    ///         .count();
+    ///     context.return_context(&[&(count as i64)])
+    /// }
+    /// ```
+    pub fn args(&mut self) -> vec::IntoIter<types::LuaGeneric> {
+        let count = self.arg_count();
+        let args: Vec<types::LuaGeneric> = (1..count + 1)
+            .map(|i| self.handle(i))
+            .collect();
+        args.into_iter()
+    }
+
+    /// Get every argument starting from the argument `from` as type T.
+    ///
+    /// Returns an empty list if there are fewer than `from` arguments, and None if any of the
+    /// arguments can not be converted.
+    pub fn rest<T: FromLua>(&mut self, from: Index) -> Option<Vec<T>> {
+        let count = self.arg_count();
+        (cmp::max(from, 1)..count + 1)
+            .map(|i| T::from_lua(self.state, i))
+            .collect()
+    }
+
+    /// Convert every argument passed to this context at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of arguments does not match the arity of T, or if any of
+    /// the arguments can not be converted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// fn repeat(state: &mut State) -> i32 {
+    ///     let mut context = Context::new(state);
+    ///     let (s, n): (String, i64) = context.get_args().unwrap();
+    ///     context.return_values((s.repeat(n as usize),))
+    /// }
+    /// ```
+    pub fn get_args<T: FromLuaMulti>(&mut self) -> error::Result<T> {
+        let count = self.arg_count();
+        T::from_lua_multi(self.state, 1, count)
+    }
+
     /// Execute valid Lua code.
     ///
     /// # Errors
     /// Returns an error if the string is not valid Lua,
     /// or a runtime error occurs during execution.
     pub fn do_string(&mut self, s: &str) -> error::Result<()> {
-        let threadstatus = self.state.do_string(&s);
+        let threadstatus = self.state.do_string(s);
         match error::get_status_from_threadstatus(threadstatus) {
             Ok(_) => {
                 error::new_luaresult_ok(())
             },
             Err(status) => {
-                error::new_luaresult_err(status, error::pop_error_from_state(&mut self.state))
+                error::new_luaresult_err(status, error::pop_error_from_state(self.state))
             }
         }
     }
@@ -203,21 +521,76 @@ impl<'a> Context<'a> {
     ///
     /// New values can not be pushed onto the old context until the new context goes out of scope;
     /// however, values pushed by the old context can still be used by the new context.
-    pub fn push_context(&mut self) -> Context {
-        Context::new(&mut self.state)
+    pub fn push_context(&mut self) -> Context<'_> {
+        Context::new(self.state)
     }
 
     /// Returns a list of values to Lua.
-    pub fn return_context(mut self, args: &[&ToLua]) -> Index {
-        // push elements in reverse order
-        for arg in args.iter().rev() {
-            arg.to_lua(&mut self.state);
+    pub fn return_context(mut self, args: &[&dyn ToLua]) -> Index {
+        let nargs = args.len() as Index;
+        self.reserve(nargs);
+        for arg in args {
+            arg.to_lua(self.state);
         }
-        for i in 0..args.len() {
-            self.state.replace(i as Index + self.target_pos);
+        self.return_pushed(nargs)
+    }
+
+    /// Same as return_context, but returns any number of values converted with ToLuaMulti.
+    pub fn return_values<T: ToLuaMulti>(mut self, values: T) -> Index {
+        self.reserve(values.count());
+        let nargs = values.to_lua_multi(self.state);
+        self.return_pushed(nargs)
+    }
+
+    /// Move the top `nargs` values of the stack down to this context's base, and return them.
+    fn return_pushed(&mut self, nargs: Index) -> Index {
+        // move the pushed values down so they sit directly above this context's base
+        let first = self.state.get_top() - nargs + 1;
+        for i in 0..nargs {
+            self.state.copy(first + i, self.target_pos + 1 + i);
+        }
+        self.target_pos += nargs;
+        nargs
+    }
+
+    /// Render every value on the stack, from the bottom to the top, for debugging.
+    ///
+    /// Each line contains a stack index, the type of the value at that index, and the value
+    /// itself. Values that can not be printed, such as tables and functions, are shown by their
+    /// address. Metamethods are never called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// # let mut state = State::new();
+    /// # let mut context = Context::new(&mut state);
+    /// context.push_integer(4);
+    /// context.push_string("foo");
+    /// assert_eq!(context.dump_stack(), "1: integer 4\n2: string \"foo\"\n");
+    /// ```
+    pub fn dump_stack(&mut self) -> String {
+        let mut ret = String::new();
+        let top = self.state.get_top();
+        for i in 1..top + 1 {
+            let t = self.state.type_of(i).unwrap_or(Type::None);
+            let typename = self.state.typename_of(t);
+            let _ = match t {
+                Type::None | Type::Nil => writeln!(ret, "{}: {}", i, typename),
+                Type::Boolean => writeln!(ret, "{}: {} {}", i, typename, self.state.to_bool(i)),
+                Type::Number if self.state.is_integer(i) => {
+                    writeln!(ret, "{}: integer {}", i, self.state.to_integer(i))
+                },
+                Type::Number => writeln!(ret, "{}: {} {}", i, typename, self.state.to_number(i)),
+                Type::String => match self.state.to_str_in_place(i) {
+                    Some(s) => writeln!(ret, "{}: {} {:?}", i, typename, s),
+                    None => writeln!(ret, "{}: {} (not valid UTF-8)", i, typename),
+                },
+                _ => writeln!(ret, "{}: {} {:p}", i, typename, self.state.to_pointer(i)),
+            };
         }
-        self.target_pos += args.len() as Index;
-        args.len() as Index
+        ret
     }
 
     /// Register a new metatable in the registry.
@@ -225,19 +598,21 @@ impl<'a> Context<'a> {
     /// Returns a tuple; the first value is if this metatable should be initialized, and the
     /// second value is the metatable itself.
     pub fn metatable_register(&mut self, name: &str) -> (bool, types::LuaTable) {
+        self.reserve(1);
         let ret = self.state.new_metatable(name);
         let i = self.state.get_top();
-        let table = types::LuaTable::new(i);
+        let table: types::LuaTable = self.handle(i);
         (ret, table)
     }
 
     /// Get a metatable from the registry.
     pub fn metatable_get(&mut self, name: &str) -> Option<types::LuaTable> {
+        self.reserve(1);
         self.state.get_metatable_from_registry(name);
         match self.state.is_table(-1) {
             true => {
                 let i = self.state.get_top();
-                let table = types::LuaTable::new(i);
+                let table: types::LuaTable = self.handle(i);
                 Some(table)
             },
             false => {
@@ -247,6 +622,14 @@ impl<'a> Context<'a> {
         }
     }
 
+    /// Get the metatable that `push_userdata` gives to values of type T, creating it if needed.
+    pub fn userdata_metatable<T: LuaUserData>(&mut self) -> types::LuaTable {
+        self.reserve(1);
+        userdata::push_metatable::<T>(self);
+        let i = self.state.get_top();
+        self.handle(i)
+    }
+
     /// Register a named metatable into the Lua registry.
     ///
     /// Takes a list of member functions, a list of metamethods, and a unique name for this
@@ -256,7 +639,7 @@ impl<'a> Context<'a> {
         let entity_library_members = self.create_lib(lib);
         let (should_set, entity_metatable) = self.metatable_register(unique_name);
         if should_set {
-            for &(ref name, ref value) in metamethods.iter() {
+            for (name, value) in metamethods.iter() {
                 entity_metatable.set_raw(self, name, value);
             }
             entity_metatable.set_raw(self, &"__index", &entity_library_members);
@@ -270,6 +653,13 @@ impl<'a> Context<'a> {
 
 impl<'a> Drop for Context<'a> {
     fn drop(&mut self) {
+        let top = self.state.get_top();
+        if cfg!(debug_assertions) && top < self.target_pos && !thread::panicking() {
+            let target_pos = self.target_pos;
+            let stack = self.dump_stack();
+            panic!("stack imbalance: values below this Context were popped (the Context started \
+                at index {}, but the top of the stack is {})\n{}", target_pos, top, stack);
+        }
         self.state.set_top(self.target_pos);
     }
 }
@@ -0,0 +1,546 @@
+//! Conversions for standard library types.
+//!
+//! The `ToLua` and `FromLua` traits belong to the `lua` crate, so they can not be implemented for
+//! standard types such as `Vec` outside of it. Instead, these types implement `ToLuaExt` and
+//! `FromLuaExt`, and the `Convert` wrapper implements `ToLua` and `FromLua` for any type that
+//! implements them, so it can be used anywhere a `ToLua` or `FromLua` is expected.
+//!
+//! Sequences, arrays and tuples become array tables, maps become tables, sets become tables
+//! where every element is a key with the value `true`, and `None` becomes nil.
+//!
+//! Integers of every width are range checked in both directions. Converting a Lua value to an
+//! integer follows `lua_tointegerx`, so floats with an exact integer value and numeric strings are
+//! accepted, but floats with a fractional part are not. Pushing an integer that does not fit in a
+//! Lua integer panics, which `try_push` turns into an error.
+//!
+//! # Examples
+//!
+//! ```
+//! # use luaext::lua::State;
+//! # use luaext::context::Context;
+//! use luaext::convert::Convert;
+//! # let mut state = State::new();
+//! # let mut context = Context::new(&mut state);
+//! context.set_global("items", &Convert(vec![1, 2, 3]));
+//! context.do_string("names = {'a', 'b'}").unwrap();
+//! let names = context.push_global("names")
+//!     .get_value::<Convert<Vec<String>>>(&mut context)
+//!     .map(Convert::into_inner);
+//! assert_eq!(names, Some(vec!["a".to_string(), "b".to_string()]));
+//! ```
+
+use lua::{Index, ToLua, FromLua, State, Type, Function, ffi};
+use std::borrow::Cow;
+use std::convert::{TryInto, TryFrom};
+use std::collections::{HashMap, BTreeMap, HashSet, BTreeSet};
+use std::hash::{Hash, BuildHasher};
+use std::slice;
+use std::str;
+use std::fmt;
+use std::any;
+use std::panic::{self, AssertUnwindSafe};
+use context::Context;
+use types;
+use value::LuaValue;
+use error;
+
+/// Trait for types that can be pushed onto the stack, including standard library types.
+pub trait ToLuaExt {
+    /// Push this value onto the stack.
+    fn to_lua_ext(&self, state: &mut State);
+}
+
+/// Trait for types that can be taken from the stack, including standard library types.
+pub trait FromLuaExt: Sized {
+    /// Convert the value at the given index, or return None if it has the wrong type.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Self>;
+}
+
+/// Wraps a value so that it implements `ToLua` or `FromLua` through `ToLuaExt` or
+/// `FromLuaExt`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Convert<T>(pub T);
+
+impl<T> Convert<T> {
+    /// Get the wrapped value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: ToLuaExt> ToLua for Convert<T> {
+    fn to_lua(&self, state: &mut State) {
+        self.0.to_lua_ext(state)
+    }
+}
+
+impl<T: FromLuaExt> FromLua for Convert<T> {
+    fn from_lua(state: &mut State, index: Index) -> Option<Convert<T>> {
+        T::from_lua_ext(state, index).map(Convert)
+    }
+}
+
+impl<T: ToLuaExt> ToLuaExt for Convert<T> {
+    fn to_lua_ext(&self, state: &mut State) {
+        self.0.to_lua_ext(state)
+    }
+}
+
+impl<T: FromLuaExt> FromLuaExt for Convert<T> {
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Convert<T>> {
+        T::from_lua_ext(state, index).map(Convert)
+    }
+}
+
+// Types that are already ToLua and FromLua convert the same way.
+macro_rules! impl_through_lua {
+    ($($t:ty),*) => {
+        $(
+            impl ToLuaExt for $t {
+                fn to_lua_ext(&self, state: &mut State) {
+                    self.to_lua(state)
+                }
+            }
+
+            impl FromLuaExt for $t {
+                fn from_lua_ext(state: &mut State, index: Index) -> Option<$t> {
+                    <$t as FromLua>::from_lua(state, index)
+                }
+            }
+        )*
+    }
+}
+
+impl_through_lua!(f64, bool, Function, LuaValue, types::LuaGeneric, types::LuaNil,
+    types::LuaBool, types::LuaInteger, types::LuaNumber, types::LuaString, types::LuaTable,
+    types::LuaFunction, types::LuaUserdata, types::LuaLightUserdata, types::LuaThread);
+
+impl ToLuaExt for str {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.push_string(self)
+    }
+}
+
+impl<T: ToLuaExt + ?Sized> ToLuaExt for &T {
+    fn to_lua_ext(&self, state: &mut State) {
+        (**self).to_lua_ext(state)
+    }
+}
+
+impl ToLuaExt for String {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.push_string(self)
+    }
+}
+
+impl FromLuaExt for String {
+    /// Only strings are converted; unlike `FromLua`, numbers and other values are not.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<String> {
+        if state.type_of(index) != Some(Type::String) {
+            return None;
+        }
+        let mut len = 0;
+        let bytes = unsafe {
+            let ptr = ffi::lua_tolstring(state.as_ptr(), index, &mut len);
+            slice::from_raw_parts(ptr as *const u8, len)
+        };
+        str::from_utf8(bytes).ok().map(ToOwned::to_owned)
+    }
+}
+
+impl<'a> ToLuaExt for Cow<'a, str> {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.push_string(self)
+    }
+}
+
+impl<'a> FromLuaExt for Cow<'a, str> {
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Cow<'a, str>> {
+        String::from_lua_ext(state, index).map(Cow::Owned)
+    }
+}
+
+impl<T: ToLuaExt + ?Sized> ToLuaExt for Box<T> {
+    fn to_lua_ext(&self, state: &mut State) {
+        (**self).to_lua_ext(state)
+    }
+}
+
+impl<T: FromLuaExt> FromLuaExt for Box<T> {
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Box<T>> {
+        T::from_lua_ext(state, index).map(Box::new)
+    }
+}
+
+impl<T: ToLuaExt> ToLuaExt for Option<T> {
+    fn to_lua_ext(&self, state: &mut State) {
+        match *self {
+            Some(ref value) => value.to_lua_ext(state),
+            None => state.push_nil(),
+        }
+    }
+}
+
+impl<T: FromLuaExt> FromLuaExt for Option<T> {
+    /// Converts nil to `Some(None)`, and any other value to `Some(Some(value))` if it can be
+    /// converted to T.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Option<T>> {
+        if state.is_none_or_nil(index) {
+            Some(None)
+        } else {
+            T::from_lua_ext(state, index).map(Some)
+        }
+    }
+}
+
+/// Push an array table containing the given values.
+fn push_array<'a, T, I>(state: &mut State, len: usize, values: I)
+        where T: ToLuaExt + 'a, I: Iterator<Item = &'a T> {
+    state.check_stack_msg(2, "not enough stack space to push table");
+    state.create_table(len as i32, 0);
+    for (i, value) in values.enumerate() {
+        value.to_lua_ext(state);
+        state.raw_seti(-2, i as i64 + 1);
+    }
+}
+
+/// Convert the element at position `n` of the table at the absolute index `index`.
+fn read_element<T: FromLuaExt>(state: &mut State, index: Index, n: i64) -> Option<T> {
+    state.raw_geti(index, n);
+    let value = T::from_lua_ext(state, -1);
+    state.pop(1);
+    value
+}
+
+/// Convert every key and value of the table at the given index.
+///
+/// Keys are copied before they are converted, so that converting them can not disturb the
+/// traversal.
+fn read_pairs<F>(state: &mut State, index: Index, mut func: F) -> Option<()>
+        where F: FnMut(&mut State, Index, Index) -> Option<()> {
+    let index = state.abs_index(index);
+    if !state.is_table(index) {
+        return None;
+    }
+    state.check_stack_msg(3, "not enough stack space to read table");
+    state.push_nil();
+    while state.next(index) {
+        state.push_value(-2);
+        let top = state.get_top();
+        if func(state, top, top - 1).is_none() {
+            state.pop(3);
+            return None;
+        }
+        state.pop(2);
+    }
+    Some(())
+}
+
+impl<T: ToLuaExt> ToLuaExt for [T] {
+    fn to_lua_ext(&self, state: &mut State) {
+        push_array(state, self.len(), self.iter())
+    }
+}
+
+impl<T: ToLuaExt> ToLuaExt for Vec<T> {
+    fn to_lua_ext(&self, state: &mut State) {
+        push_array(state, self.len(), self.iter())
+    }
+}
+
+impl<T: FromLuaExt> FromLuaExt for Vec<T> {
+    /// Converts the elements of an array table, from 1 up to its length.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<Vec<T>> {
+        let index = state.abs_index(index);
+        if !state.is_table(index) {
+            return None;
+        }
+        state.check_stack_msg(1, "not enough stack space to read table");
+        let len = state.raw_len(index) as i64;
+        (1..len + 1)
+            .map(|n| read_element(state, index, n))
+            .collect()
+    }
+}
+
+impl<T: ToLuaExt, const N: usize> ToLuaExt for [T; N] {
+    fn to_lua_ext(&self, state: &mut State) {
+        push_array(state, N, self.iter())
+    }
+}
+
+impl<T: FromLuaExt, const N: usize> FromLuaExt for [T; N] {
+    /// Converts an array table, which must have exactly N elements.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<[T; N]> {
+        let values: Vec<T> = Vec::from_lua_ext(state, index)?;
+        values.try_into().ok()
+    }
+}
+
+impl<K: ToLuaExt, V: ToLuaExt, S> ToLuaExt for HashMap<K, V, S> {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.check_stack_msg(3, "not enough stack space to push table");
+        state.create_table(0, self.len() as i32);
+        for (key, value) in self {
+            key.to_lua_ext(state);
+            value.to_lua_ext(state);
+            state.raw_set(-3);
+        }
+    }
+}
+
+impl<K, V, S> FromLuaExt for HashMap<K, V, S>
+        where K: FromLuaExt + Eq + Hash, V: FromLuaExt, S: BuildHasher + Default {
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<HashMap<K, V, S>> {
+        let mut map = HashMap::default();
+        read_pairs(state, index, |state, key, value| {
+            map.insert(K::from_lua_ext(state, key)?, V::from_lua_ext(state, value)?);
+            Some(())
+        })?;
+        Some(map)
+    }
+}
+
+impl<K: ToLuaExt, V: ToLuaExt> ToLuaExt for BTreeMap<K, V> {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.check_stack_msg(3, "not enough stack space to push table");
+        state.create_table(0, self.len() as i32);
+        for (key, value) in self {
+            key.to_lua_ext(state);
+            value.to_lua_ext(state);
+            state.raw_set(-3);
+        }
+    }
+}
+
+impl<K: FromLuaExt + Ord, V: FromLuaExt> FromLuaExt for BTreeMap<K, V> {
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<BTreeMap<K, V>> {
+        let mut map = BTreeMap::new();
+        read_pairs(state, index, |state, key, value| {
+            map.insert(K::from_lua_ext(state, key)?, V::from_lua_ext(state, value)?);
+            Some(())
+        })?;
+        Some(map)
+    }
+}
+
+impl<T: ToLuaExt, S> ToLuaExt for HashSet<T, S> {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.check_stack_msg(3, "not enough stack space to push table");
+        state.create_table(0, self.len() as i32);
+        for value in self {
+            value.to_lua_ext(state);
+            state.push_bool(true);
+            state.raw_set(-3);
+        }
+    }
+}
+
+impl<T, S> FromLuaExt for HashSet<T, S>
+        where T: FromLuaExt + Eq + Hash, S: BuildHasher + Default {
+    /// Converts the keys of a table, ignoring its values.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<HashSet<T, S>> {
+        let mut set = HashSet::default();
+        read_pairs(state, index, |state, key, _| {
+            set.insert(T::from_lua_ext(state, key)?);
+            Some(())
+        })?;
+        Some(set)
+    }
+}
+
+impl<T: ToLuaExt> ToLuaExt for BTreeSet<T> {
+    fn to_lua_ext(&self, state: &mut State) {
+        state.check_stack_msg(3, "not enough stack space to push table");
+        state.create_table(0, self.len() as i32);
+        for value in self {
+            value.to_lua_ext(state);
+            state.push_bool(true);
+            state.raw_set(-3);
+        }
+    }
+}
+
+impl<T: FromLuaExt + Ord> FromLuaExt for BTreeSet<T> {
+    /// Converts the keys of a table, ignoring its values.
+    fn from_lua_ext(state: &mut State, index: Index) -> Option<BTreeSet<T>> {
+        let mut set = BTreeSet::new();
+        read_pairs(state, index, |state, key, _| {
+            set.insert(T::from_lua_ext(state, key)?);
+            Some(())
+        })?;
+        Some(set)
+    }
+}
+
+/// Convert the value at the given index to an integer of type T.
+///
+/// # Errors
+///
+/// Returns an error if the value is not a number, if it is a float without an exact integer
+/// representation, or if it does not fit in T.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::lua::State;
+/// # use luaext::context::Context;
+/// # use luaext::convert;
+/// # let mut state = State::new();
+/// # let mut context = Context::new(&mut state);
+/// context.push_integer(300);
+/// context.push_number(2.0);
+/// assert!(convert::to_integer::<u8>(context.get_state(), 1).is_err());
+/// assert_eq!(convert::to_integer::<u8>(context.get_state(), 2).unwrap(), 2);
+/// ```
+pub fn to_integer<T: TryFrom<i64>>(state: &mut State, index: Index) -> error::Result<T> {
+    let value = match state.to_integerx(index) {
+        Some(value) => value,
+        None if state.is_number(index) => {
+            let number = state.to_number(index);
+            return error::new_luaresult_err(error::LuaErrorType::TypeError,
+                format!("number {} has no integer representation", number));
+        },
+        None => {
+            let typename = state.typename_at(index);
+            return error::new_luaresult_err(error::LuaErrorType::TypeError,
+                format!("expected an integer, got {}", typename));
+        },
+    };
+    match T::try_from(value) {
+        Ok(value) => error::new_luaresult_ok(value),
+        Err(_) => error::new_luaresult_err(error::LuaErrorType::TypeError,
+            format!("integer {} is out of range for {}", value, any::type_name::<T>())),
+    }
+}
+
+/// Push an integer, panicking with a `LuaError` payload if it does not fit in a Lua integer.
+///
+/// Raising a Lua error instead would jump over the Rust frames that are pushing the value
+/// without running their destructors.
+fn push_integer<T: Copy + fmt::Display>(state: &mut State, value: T) where i64: TryFrom<T> {
+    match i64::try_from(value) {
+        Ok(value) => state.push_integer(value),
+        Err(_) => {
+            let message = format!("integer {} is out of range for a Lua integer", value);
+            if let Err(err) = error::new_luaresult_err::<()>(error::LuaErrorType::TypeError,
+                    message) {
+                panic::panic_any(err);
+            }
+        }
+    }
+}
+
+/// Push a value, returning an error instead of panicking if it can not be converted.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::lua::State;
+/// # use luaext::context::Context;
+/// use luaext::convert;
+/// # let mut state = State::new();
+/// # let mut context = Context::new(&mut state);
+/// assert!(convert::try_push(&mut context, &vec![1u64, 2]).is_ok());
+/// assert!(convert::try_push(&mut context, &vec![1u64, u64::MAX]).is_err());
+/// ```
+pub fn try_push<T: ToLuaExt + ?Sized>(context: &mut Context, value: &T)
+        -> error::Result<types::LuaGeneric> {
+    context.try_reserve(1)?;
+    let top = context.get_state().get_top();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| value.to_lua_ext(context.get_state())));
+    match result {
+        Ok(()) => error::new_luaresult_ok(context.handle(top + 1)),
+        Err(payload) => match payload.downcast::<error::LuaError>() {
+            Ok(err) => {
+                // drop whatever was pushed before the error, such as an unfinished table
+                context.get_state().set_top(top);
+                Err(*err)
+            },
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ty),*) => {
+        $(
+            impl ToLuaExt for $t {
+                /// Panics with a `LuaError` payload if the value does not fit in a Lua integer.
+                fn to_lua_ext(&self, state: &mut State) {
+                    push_integer(state, *self)
+                }
+            }
+
+            impl FromLuaExt for $t {
+                /// Returns None if the value is not an integer in range for this type; use
+                /// `to_integer` to find out why.
+                fn from_lua_ext(state: &mut State, index: Index) -> Option<$t> {
+                    to_integer(state, index).ok()
+                }
+            }
+        )*
+    }
+}
+
+impl_integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, usize);
+
+/// A u64 that is stored in a Lua integer bit for bit.
+///
+/// Values above `i64::MAX` become negative Lua integers instead of raising an error, and negative
+/// Lua integers convert back to the same u64. This suits hashes and identifiers, where the bits
+/// matter but arithmetic in Lua does not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U64Bits(pub u64);
+
+impl ToLua for U64Bits {
+    fn to_lua(&self, state: &mut State) {
+        state.push_integer(self.0 as i64)
+    }
+}
+
+impl FromLua for U64Bits {
+    fn from_lua(state: &mut State, index: Index) -> Option<U64Bits> {
+        to_integer::<i64>(state, index).ok().map(|value| U64Bits(value as u64))
+    }
+}
+
+impl_through_lua!(U64Bits);
+
+macro_rules! impl_tuple {
+    ($count:expr; $($name:ident $pos:expr),*) => {
+        impl<$($name: ToLuaExt),*> ToLuaExt for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn to_lua_ext(&self, state: &mut State) {
+                let ($(ref $name,)*) = *self;
+                state.check_stack_msg(2, "not enough stack space to push table");
+                state.create_table($count, 0);
+                $(
+                    $name.to_lua_ext(state);
+                    state.raw_seti(-2, $pos + 1);
+                )*
+            }
+        }
+
+        impl<$($name: FromLuaExt),*> FromLuaExt for ($($name,)*) {
+            /// Converts an array table, which must have exactly as many elements as the tuple.
+            fn from_lua_ext(state: &mut State, index: Index) -> Option<($($name,)*)> {
+                let index = state.abs_index(index);
+                if !state.is_table(index) || state.raw_len(index) != $count {
+                    return None;
+                }
+                state.check_stack_msg(1, "not enough stack space to read table");
+                Some(($(read_element::<$name>(state, index, $pos + 1)?,)*))
+            }
+        }
+    }
+}
+
+impl_tuple!(1; A 0);
+impl_tuple!(2; A 0, B 1);
+impl_tuple!(3; A 0, B 1, C 2);
+impl_tuple!(4; A 0, B 1, C 2, D 3);
+impl_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
@@ -1,7 +1,11 @@
 use std::error;
 use std::fmt;
+use std::io::{self, Write};
 use std::result;
+use std::process;
 use lua;
+use lua::ffi::lua_State;
+use lua::libc::c_int;
 
 /// The type of error that occured
 #[derive(Copy, Clone, Debug)]
@@ -12,6 +16,8 @@ pub enum LuaErrorType {
     GcError,
     MessageHandlerError,
     FileError,
+    StackError,
+    TypeError,
 }
 
 /// A lua error
@@ -44,7 +50,7 @@ impl error::Error for LuaError {
         &self.message
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
@@ -60,8 +66,8 @@ pub fn new_luaresult_ok<T>(value: T) -> self::Result<T> {
 /// Create a new Lua Error result with a type and an error
 pub fn new_luaresult_err<T>(status: LuaErrorType, message: String) -> self::Result<T> {
     Err(LuaError{
-        status: status,
-        message: message
+        status,
+        message
     })
 }
 
@@ -81,8 +87,39 @@ pub fn get_status_from_threadstatus(status: lua::ThreadStatus)
 }
 
 /// Get the error message from a lua state (always the last value on the stack)
+///
+/// The message is read without calling `__tostring`, which could raise another error; an error
+/// object that is not a string or a number is described by its type instead.
 pub fn pop_error_from_state(state: &mut lua::State) -> String {
-    let ret = state.to_str(-1).unwrap().into();
+    let ret = match state.to_str_in_place(-1) {
+        Some(message) => message.into(),
+        None => format!("(error object is a {} value)", state.typename_at(-1)),
+    };
     state.pop(1);
     ret
 }
+
+/// Lua panic function that writes the error to stderr and aborts the process.
+extern "C" fn abort_on_panic(state: *mut lua_State) -> c_int {
+    let mut state = unsafe { lua::State::from_ptr(state) };
+    let message = pop_error_from_state(&mut state);
+    let _ = writeln!(io::stderr(), "PANIC: unprotected error in call to Lua API ({})", message);
+    process::abort()
+}
+
+/// Get a Lua panic function for errors that are raised outside of any protected call.
+///
+/// The operations of this crate that can raise errors, such as `Context::set_global` or
+/// `LuaTable::get`, run inside a protected call and turn an error into a Rust panic whose
+/// payload is the `LuaError`, which can be recovered with `std::panic::catch_unwind`. Only an
+/// error raised by calling the State directly, or a memory error outside of a protected call,
+/// reaches the panic function. A Rust panic can not unwind through Lua's C functions, so this
+/// function writes the error to stderr and aborts the process instead of returning to Lua.
+pub fn panic_handler() -> lua::Function {
+    Some(abort_on_panic)
+}
+
+/// Install the panic function returned by `panic_handler` into a State.
+pub fn set_panic_handler(state: &mut lua::State) {
+    state.at_panic(panic_handler());
+}
@@ -0,0 +1,60 @@
+//! Stack balance checks for Rust functions called from Lua.
+
+use lua::State;
+use lua::libc::c_int;
+
+/// Wrap a `fn(&mut State) -> i32` as a `Function`, the same way `lua_func!` does, but check that
+/// the function leaves the stack balanced when built with debug assertions.
+///
+/// A function is balanced if, when it returns `n`, the stack holds exactly its arguments followed
+/// by `n` return values; this is what `Context::return_context` produces. If the function leaves
+/// extra values on the stack, or returns a count that does not match the values it placed, a Lua
+/// error naming the function is raised. Without debug assertions, this is the same as
+/// `lua_func!`.
+///
+/// # Examples
+///
+/// ```
+/// # #[macro_use] extern crate luaext;
+/// # use luaext::lua::State;
+/// # use luaext::context::Context;
+/// fn add(state: &mut State) -> i32 {
+///     let mut context = Context::new(state);
+///     let a: i64 = context.get_arg_typed(1).unwrap();
+///     let b: i64 = context.get_arg_typed(2).unwrap();
+///     context.return_context(&[&(a + b)])
+/// }
+///
+/// # fn main() {
+/// let mut state = State::new();
+/// let mut context = Context::new(&mut state);
+/// context.set_global("add", &lua_func_guarded!(add));
+/// context.do_string("foo = add(1, 2)").unwrap();
+/// # }
+/// ```
+#[macro_export]
+macro_rules! lua_func_guarded {
+    ($func:path) => {
+        $crate::lua::macros::_wrap(|s| $crate::guard::_call_guarded(s, stringify!($func), $func))
+    }
+}
+
+#[doc(hidden)]
+pub fn _call_guarded(state: &mut State, name: &str, func: fn(&mut State) -> c_int) -> c_int {
+    if !cfg!(debug_assertions) {
+        return func(state);
+    }
+    let top_prev = state.get_top();
+    let nresults = func(state);
+    let top_post = state.get_top();
+    if nresults >= 0 && top_post == top_prev + nresults {
+        return nresults;
+    }
+    let message = format!("stack imbalance in Rust function '{}': called with {} arguments and \
+        returned {} values, but left {} values on the stack (expected {})",
+        name, top_prev, nresults, top_post, top_prev + nresults);
+    state.check_stack(1);
+    state.push_string(&message);
+    drop(message);
+    state.error()
+}
@@ -2,9 +2,27 @@
 
 #[macro_use]
 pub extern crate lua;
+#[cfg(feature = "serde")]
+extern crate serde as serde_lib;
 pub mod context;
 pub mod types;
 pub mod error;
+pub mod runtime;
+pub mod libs;
+pub mod multi;
+pub mod value;
+pub mod convert;
+pub mod userdata;
+pub mod class;
+#[cfg(feature = "serde")]
+pub mod serde;
+#[macro_use]
+pub mod guard;
+mod callback;
+mod check;
+mod protect;
 mod test;
 
 pub use context::Context;
+pub use runtime::Lua;
+pub use value::LuaValue;
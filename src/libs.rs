@@ -0,0 +1,126 @@
+use lua::{State, Library};
+use std::ops::{BitOr, BitOrAssign, BitAnd, Sub};
+
+/// A set of Lua standard libraries.
+///
+/// Sets can be combined using the `|` operator.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::libs::LuaLibs;
+/// let libs = LuaLibs::BASE | LuaLibs::STRING | LuaLibs::MATH;
+/// assert!(libs.contains(LuaLibs::STRING));
+/// assert!(!libs.contains(LuaLibs::IO));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LuaLibs {
+    bits: u32
+}
+
+/// Every library paired with the flag that selects it, in the order they are opened.
+const LIBRARIES: &[(LuaLibs, Library)] = &[
+    (LuaLibs::BASE, Library::Base),
+    (LuaLibs::PACKAGE, Library::Package),
+    (LuaLibs::COROUTINE, Library::Coroutine),
+    (LuaLibs::TABLE, Library::Table),
+    (LuaLibs::IO, Library::Io),
+    (LuaLibs::OS, Library::Os),
+    (LuaLibs::STRING, Library::String),
+    (LuaLibs::UTF8, Library::Utf8),
+    (LuaLibs::MATH, Library::Math),
+    (LuaLibs::DEBUG, Library::Debug),
+];
+
+impl LuaLibs {
+    /// No libraries.
+    pub const NONE: LuaLibs = LuaLibs { bits: 0 };
+    /// The base library (`print`, `pairs`, `pcall`, etc.).
+    pub const BASE: LuaLibs = LuaLibs { bits: 1 };
+    /// The `package` library, which provides `require`.
+    pub const PACKAGE: LuaLibs = LuaLibs { bits: 1 << 1 };
+    /// The `coroutine` library.
+    pub const COROUTINE: LuaLibs = LuaLibs { bits: 1 << 2 };
+    /// The `table` library.
+    pub const TABLE: LuaLibs = LuaLibs { bits: 1 << 3 };
+    /// The `io` library.
+    pub const IO: LuaLibs = LuaLibs { bits: 1 << 4 };
+    /// The `os` library.
+    pub const OS: LuaLibs = LuaLibs { bits: 1 << 5 };
+    /// The `string` library.
+    pub const STRING: LuaLibs = LuaLibs { bits: 1 << 6 };
+    /// The `utf8` library.
+    pub const UTF8: LuaLibs = LuaLibs { bits: 1 << 7 };
+    /// The `math` library.
+    pub const MATH: LuaLibs = LuaLibs { bits: 1 << 8 };
+    /// The `debug` library.
+    pub const DEBUG: LuaLibs = LuaLibs { bits: 1 << 9 };
+    /// Every standard library.
+    pub const ALL: LuaLibs = LuaLibs { bits: (1 << 10) - 1 };
+    /// Every standard library that can not reach outside of Lua; everything except `package`,
+    /// `io`, `os`, and `debug`.
+    pub const SAFE: LuaLibs = LuaLibs {
+        bits: LuaLibs::BASE.bits | LuaLibs::COROUTINE.bits | LuaLibs::TABLE.bits
+            | LuaLibs::STRING.bits | LuaLibs::UTF8.bits | LuaLibs::MATH.bits
+    };
+
+    /// Get the raw bits of this set.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Check if this set contains every library in another set.
+    pub fn contains(&self, other: LuaLibs) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Check if this set contains no libraries.
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+}
+
+impl Default for LuaLibs {
+    fn default() -> LuaLibs {
+        LuaLibs::ALL
+    }
+}
+
+impl BitOr for LuaLibs {
+    type Output = LuaLibs;
+    fn bitor(self, other: LuaLibs) -> LuaLibs {
+        LuaLibs { bits: self.bits | other.bits }
+    }
+}
+
+impl BitOrAssign for LuaLibs {
+    fn bitor_assign(&mut self, other: LuaLibs) {
+        self.bits |= other.bits;
+    }
+}
+
+impl BitAnd for LuaLibs {
+    type Output = LuaLibs;
+    fn bitand(self, other: LuaLibs) -> LuaLibs {
+        LuaLibs { bits: self.bits & other.bits }
+    }
+}
+
+impl Sub for LuaLibs {
+    type Output = LuaLibs;
+    fn sub(self, other: LuaLibs) -> LuaLibs {
+        LuaLibs { bits: self.bits & !other.bits }
+    }
+}
+
+/// Open the given standard libraries in a Lua state.
+///
+/// Each library is loaded using `luaL_requiref`, so it is both set as a global and registered
+/// in `package.loaded`. Libraries that are not in the set are never loaded.
+pub fn open_libs(state: &mut State, libs: LuaLibs) {
+    for &(flag, lib) in LIBRARIES {
+        if libs.contains(flag) {
+            state.load_library(lib);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Conversions between Rust values and multiple Lua values.
+//!
+//! Lua functions take and return any number of values. `ToLuaMulti` and `FromLuaMulti` convert
+//! between such lists of values and tuples, `Vec`s, and `Variadic`s, so that arguments and
+//! return values can be converted in a single step.
+
+use lua::{Index, ToLua, FromLua, State};
+use std::ops::{Deref, DerefMut};
+use std::any;
+use error;
+
+/// Trait for types that can be pushed onto the stack as any number of values.
+pub trait ToLuaMulti {
+    /// Get the number of values `to_lua_multi` will push.
+    fn count(&self) -> Index;
+
+    /// Push every value onto the stack, and return the number of values pushed.
+    fn to_lua_multi(&self, state: &mut State) -> Index;
+}
+
+/// Trait for types that can be taken from a list of values on the stack.
+pub trait FromLuaMulti: Sized {
+    /// Get the number of values this type expects, or None if it accepts any number of values.
+    fn arity() -> Option<Index>;
+
+    /// Convert the `count` values starting at the index `start` to a value of this type.
+    ///
+    /// Tuples treat missing trailing values as nil, so that optional arguments, such as a
+    /// `Convert<Option<T>>`, can be left out.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are more values than this type's arity, if a missing value can
+    /// not be nil, or if any value can not be converted.
+    fn from_lua_multi(state: &mut State, start: Index, count: Index) -> error::Result<Self>;
+}
+
+/// A list of values that is converted to and from any number of Lua values.
+///
+/// Unlike a `Vec`, which may be converted to a Lua table, a `Variadic` always represents
+/// separate values, such as the `...` arguments of a function.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+/// Convert a single value, reporting its position in the list of values on failure.
+fn convert_value<T: FromLua>(state: &mut State, index: Index, position: Index)
+        -> error::Result<T> {
+    match T::from_lua(state, index) {
+        Some(value) => error::new_luaresult_ok(value),
+        None => {
+            let typename = state.typename_at(index);
+            error::new_luaresult_err(error::LuaErrorType::TypeError,
+                format!("value #{} (a {} value) can not be converted to {}",
+                    position, typename, any::type_name::<T>()))
+        }
+    }
+}
+
+fn arity_error<T>(expected: Index, count: Index) -> error::Result<T> {
+    error::new_luaresult_err(error::LuaErrorType::TypeError,
+        format!("expected {} values, got {}", expected, count))
+}
+
+/// Check that there are at most `expected` values, and that there is room to push the nil that
+/// any missing values are converted from.
+fn check_arity(state: &mut State, expected: Index, count: Index) -> error::Result<()> {
+    if count > expected {
+        arity_error(expected, count)
+    } else if count < expected && !state.check_stack(1) {
+        error::new_luaresult_err(error::LuaErrorType::StackError,
+            "not enough stack space to convert values".to_string())
+    } else {
+        error::new_luaresult_ok(())
+    }
+}
+
+/// Convert the value at `position` in a list of `count` values, where a missing value must be
+/// one that can be nil.
+fn convert_arg<T: FromLua>(state: &mut State, start: Index, position: Index, expected: Index,
+        count: Index) -> error::Result<T> {
+    if position <= count {
+        return convert_value(state, start + position - 1, position);
+    }
+    // the slots above the values may hold other values, so a missing value is read from a nil
+    state.push_nil();
+    let value = T::from_lua(state, -1);
+    state.pop(1);
+    match value {
+        Some(value) => error::new_luaresult_ok(value),
+        None => arity_error(expected, count),
+    }
+}
+
+impl<T: ToLua> ToLuaMulti for Vec<T> {
+    fn count(&self) -> Index {
+        self.len() as Index
+    }
+
+    fn to_lua_multi(&self, state: &mut State) -> Index {
+        for value in self {
+            value.to_lua(state);
+        }
+        self.len() as Index
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Vec<T> {
+    fn arity() -> Option<Index> {
+        None
+    }
+
+    fn from_lua_multi(state: &mut State, start: Index, count: Index) -> error::Result<Vec<T>> {
+        (0..count)
+            .map(|i| convert_value(state, start + i, i + 1))
+            .collect()
+    }
+}
+
+impl<T: ToLua> ToLuaMulti for Variadic<T> {
+    fn count(&self) -> Index {
+        self.0.count()
+    }
+
+    fn to_lua_multi(&self, state: &mut State) -> Index {
+        self.0.to_lua_multi(state)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn arity() -> Option<Index> {
+        None
+    }
+
+    fn from_lua_multi(state: &mut State, start: Index, count: Index)
+            -> error::Result<Variadic<T>> {
+        Vec::from_lua_multi(state, start, count).map(Variadic)
+    }
+}
+
+macro_rules! impl_tuple {
+    ($count:expr; $($name:ident $pos:expr),*) => {
+        impl<$($name: ToLua),*> ToLuaMulti for ($($name,)*) {
+            fn count(&self) -> Index {
+                $count
+            }
+
+            #[allow(non_snake_case, unused_variables)]
+            fn to_lua_multi(&self, state: &mut State) -> Index {
+                let ($(ref $name,)*) = *self;
+                $($name.to_lua(state);)*
+                $count
+            }
+        }
+
+        impl<$($name: FromLua),*> FromLuaMulti for ($($name,)*) {
+            fn arity() -> Option<Index> {
+                Some($count)
+            }
+
+            #[allow(unused_variables)]
+            fn from_lua_multi(state: &mut State, start: Index, count: Index)
+                    -> error::Result<($($name,)*)> {
+                check_arity(state, $count, count)?;
+                error::new_luaresult_ok(($(
+                    convert_arg::<$name>(state, start, $pos + 1, $count, count)?,
+                )*))
+            }
+        }
+    }
+}
+
+impl_tuple!(0;);
+impl_tuple!(1; A 0);
+impl_tuple!(2; A 0, B 1);
+impl_tuple!(3; A 0, B 1, C 2);
+impl_tuple!(4; A 0, B 1, C 2, D 3);
+impl_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
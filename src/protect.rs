@@ -0,0 +1,100 @@
+//! Protected calls for operations that can raise Lua errors.
+//!
+//! Operations that can call metamethods, such as indexing a table or calling a function, raise
+//! a Lua error when the metamethod does. Outside of a protected call that error reaches the
+//! panic function, and a Rust panic must not unwind out of it through Lua's C frames. These
+//! operations are instead run by small C functions inside `lua_pcall`, and an error is turned
+//! into a Rust panic once the call has returned to Rust.
+
+use lua::{State, Function};
+use lua::ffi::{self, lua_State};
+use lua::libc::c_int;
+use std::panic;
+use error;
+
+/// Call `func` in protected mode with the top `nargs` values as its arguments, leaving
+/// `nresults` results in their place.
+///
+/// There must be room on the stack for one more value.
+///
+/// # Panics
+///
+/// Panics with a `LuaError` payload if `func` raises an error.
+fn protect(state: &mut State, func: Function, nargs: c_int, nresults: c_int) {
+    state.push_fn(func);
+    state.insert(-(nargs + 1));
+    if let Err(status) = error::get_status_from_threadstatus(state.pcall(nargs, nresults, 0)) {
+        let message = error::pop_error_from_state(state);
+        if let Err(err) = error::new_luaresult_err::<()>(status, message) {
+            panic::panic_any(err);
+        }
+    }
+}
+
+unsafe extern "C" fn get_table(state: *mut lua_State) -> c_int {
+    ffi::lua_gettable(state, 1);
+    1
+}
+
+unsafe extern "C" fn set_table(state: *mut lua_State) -> c_int {
+    ffi::lua_settable(state, 1);
+    0
+}
+
+unsafe extern "C" fn len(state: *mut lua_State) -> c_int {
+    let len = ffi::luaL_len(state, 1);
+    ffi::lua_pushinteger(state, len);
+    1
+}
+
+unsafe extern "C" fn call(state: *mut lua_State) -> c_int {
+    ffi::lua_call(state, ffi::lua_gettop(state) - 1, ffi::LUA_MULTRET);
+    ffi::lua_gettop(state)
+}
+
+/// Pop a key and push `t[key]`, where t is the value at the given index.
+pub(crate) fn get_table_at(state: &mut State, index: c_int) {
+    let index = state.abs_index(index);
+    state.push_value(index);
+    state.insert(-2);
+    protect(state, Some(get_table), 2, 1);
+}
+
+/// Pop a key and a value and do `t[key] = value`, where t is the value at the given index.
+pub(crate) fn set_table_at(state: &mut State, index: c_int) {
+    let index = state.abs_index(index);
+    state.push_value(index);
+    state.insert(-3);
+    protect(state, Some(set_table), 3, 0);
+}
+
+/// Push the global with the given name.
+pub(crate) fn get_global(state: &mut State, name: &str) {
+    state.push_global_table();
+    state.push_string(name);
+    protect(state, Some(get_table), 2, 1);
+}
+
+/// Pop a value and set the global with the given name to it.
+pub(crate) fn set_global(state: &mut State, name: &str) {
+    state.push_global_table();
+    state.insert(-2);
+    state.push_string(name);
+    state.insert(-2);
+    protect(state, Some(set_table), 3, 0);
+}
+
+/// Get the length of the value at the given index, as the `#` operator does.
+pub(crate) fn len_at(state: &mut State, index: c_int) -> i64 {
+    let index = state.abs_index(index);
+    state.push_value(index);
+    protect(state, Some(len), 1, 1);
+    let len = state.to_integer(-1);
+    state.pop(1);
+    len
+}
+
+/// Call the function below the top `nargs` values, as `lua_call` does.
+pub(crate) fn call_function(state: &mut State, nargs: c_int, nresults: c_int) {
+    protect(state, Some(call), nargs + 1, nresults);
+}
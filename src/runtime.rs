@@ -0,0 +1,218 @@
+use lua::{State, Function, Hook, HookMask, GcOption, Allocator};
+use lua::libc::{c_void, size_t};
+use context::Context;
+use libs::{self, LuaLibs};
+use error;
+use std::ptr;
+
+/// Globals that are removed from a sandboxed runtime.
+const SANDBOX_REMOVED: &[&str] = &[
+    "dofile", "loadfile", "load", "require", "collectgarbage",
+    "package", "io", "os", "debug",
+];
+
+/// Bookkeeping for a runtime with a memory limit.
+///
+/// Wraps the allocator the State was created with so that memory allocated before the limit was
+/// installed can still be released by it.
+struct MemoryState {
+    used: usize,
+    limit: usize,
+    inner: Allocator,
+    inner_ud: *mut c_void,
+}
+
+unsafe extern "C" fn limited_alloc(ud: *mut c_void, ptr: *mut c_void, old_size: size_t,
+        new_size: size_t) -> *mut c_void {
+    let memory = &mut *(ud as *mut MemoryState);
+    // when ptr is null, old_size is a type indicator rather than a size
+    let old_size = if ptr.is_null() { 0 } else { old_size };
+    let used = memory.used.saturating_sub(old_size);
+    if new_size > old_size && used + new_size > memory.limit {
+        return ptr::null_mut();
+    }
+    let inner = memory.inner.expect("Lua state has no allocator");
+    let result = inner(memory.inner_ud, ptr, old_size, new_size);
+    if !result.is_null() || new_size == 0 {
+        memory.used = used + new_size;
+    }
+    result
+}
+
+/// An owned Lua runtime.
+///
+/// Unlike a `Context`, which borrows a State, a `Lua` owns its State, so it can be stored in
+/// other structs. Use `Lua::context` to get a root Context for working with the runtime.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::runtime::Lua;
+/// let mut lua = Lua::builder()
+///     .memory_limit(16 * 1024 * 1024)
+///     .sandbox(true)
+///     .build();
+/// let mut context = lua.context();
+/// context.do_string("foo = math.max(1, 2)").unwrap();
+/// assert_eq!(Some(2), context.push_global("foo").get_value(&mut context));
+/// ```
+pub struct Lua {
+    // state must be dropped before memory, since closing the state frees memory through it
+    state: State,
+    memory: Option<Box<MemoryState>>,
+}
+
+unsafe impl Send for Lua {}
+
+impl Lua {
+    /// Create a new runtime with every standard library opened.
+    pub fn new() -> Lua {
+        LuaBuilder::new().build()
+    }
+
+    /// Create a builder to configure a new runtime.
+    pub fn builder() -> LuaBuilder {
+        LuaBuilder::new()
+    }
+
+    /// Create a new root Context for this runtime.
+    ///
+    /// Any value pushed using the returned Context is popped when it goes out of scope.
+    pub fn context(&mut self) -> Context<'_> {
+        Context::new(&mut self.state)
+    }
+
+    /// Get this runtime's contained state.
+    pub fn get_state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Get the number of bytes currently allocated by this runtime.
+    pub fn used_memory(&mut self) -> usize {
+        match self.memory {
+            Some(ref memory) => memory.used,
+            None => {
+                let kbytes = self.state.gc(GcOption::Count, 0) as usize;
+                let bytes = self.state.gc(GcOption::CountBytes, 0) as usize;
+                kbytes * 1024 + bytes
+            }
+        }
+    }
+
+    /// Get the memory limit of this runtime in bytes, if there is one.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory.as_ref().map(|memory| memory.limit)
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Lua {
+        Lua::new()
+    }
+}
+
+/// Configures and creates a `Lua` runtime.
+pub struct LuaBuilder {
+    libs: LuaLibs,
+    memory_limit: Option<usize>,
+    hook: Option<(Hook, HookMask, i32)>,
+    panic_handler: Option<Function>,
+    sandbox: bool,
+}
+
+impl LuaBuilder {
+    /// Create a new builder.
+    ///
+    /// By default every standard library is opened, there is no memory limit and no hook,
+    /// errors raised outside of any protected call abort the process (see `error::panic_handler`),
+    /// and the runtime is not sandboxed.
+    pub fn new() -> LuaBuilder {
+        LuaBuilder {
+            libs: LuaLibs::ALL,
+            memory_limit: None,
+            hook: None,
+            panic_handler: Some(error::panic_handler()),
+            sandbox: false,
+        }
+    }
+
+    /// Set which standard libraries should be opened.
+    pub fn libs(mut self, libs: LuaLibs) -> LuaBuilder {
+        self.libs = libs;
+        self
+    }
+
+    /// Limit the amount of memory the runtime can allocate, in bytes.
+    ///
+    /// Allocations beyond this limit fail, which Lua reports as a memory error.
+    pub fn memory_limit(mut self, limit: usize) -> LuaBuilder {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Set a debug hook, equivalent to the Lua `debug.sethook` function.
+    pub fn hook(mut self, func: Hook, mask: HookMask, count: i32) -> LuaBuilder {
+        self.hook = Some((func, mask, count));
+        self
+    }
+
+    /// Set the function Lua calls when an error happens outside of any protected call.
+    ///
+    /// Passing `None` keeps Lua's default panic function. Lua calls `abort()` if the panic
+    /// function returns, and the panic function must not unwind.
+    pub fn panic_handler(mut self, func: Option<Function>) -> LuaBuilder {
+        self.panic_handler = func;
+        self
+    }
+
+    /// Set whether the runtime should be sandboxed.
+    ///
+    /// A sandboxed runtime has no access to the filesystem, the operating system, the debug
+    /// library, or any function that can load code (`dofile`, `loadfile`, `load`, `require`).
+    pub fn sandbox(mut self, value: bool) -> LuaBuilder {
+        self.sandbox = value;
+        self
+    }
+
+    /// Create the runtime.
+    pub fn build(self) -> Lua {
+        let mut state = State::new();
+        if let Some(func) = self.panic_handler {
+            state.at_panic(func);
+        }
+        let memory = self.memory_limit.map(|limit| {
+            let (inner, inner_ud) = state.get_alloc_fn();
+            let kbytes = state.gc(GcOption::Count, 0) as usize;
+            let bytes = state.gc(GcOption::CountBytes, 0) as usize;
+            let mut memory = Box::new(MemoryState {
+                used: kbytes * 1024 + bytes,
+                limit,
+                inner,
+                inner_ud,
+            });
+            let ud = &mut *memory as *mut MemoryState as *mut c_void;
+            state.set_alloc_fn(Some(limited_alloc), ud);
+            memory
+        });
+        libs::open_libs(&mut state, self.libs);
+        if self.sandbox {
+            for name in SANDBOX_REMOVED {
+                state.push_nil();
+                state.set_global(name);
+            }
+        }
+        if let Some((func, mask, count)) = self.hook {
+            state.set_hook(func, mask, count);
+        }
+        Lua {
+            state,
+            memory,
+        }
+    }
+}
+
+impl Default for LuaBuilder {
+    fn default() -> LuaBuilder {
+        LuaBuilder::new()
+    }
+}
@@ -0,0 +1,741 @@
+//! Conversions between Lua values and any type that implements Serialize or Deserialize.
+//!
+//! Requires the `serde` feature.
+//!
+//! Structs and maps become tables with a field for each entry, sequences and tuples become array
+//! tables, and `None` and `()` become nil. Unit enum variants become the variant's name, and all
+//! other enum variants become a table with a single field named after the variant.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate serde;
+//! # extern crate luaext;
+//! # use serde::{Serialize, Deserialize};
+//! # use luaext::lua::State;
+//! # use luaext::context::Context;
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Config {
+//!     name: String,
+//!     ports: Vec<u16>,
+//! }
+//!
+//! # fn main() {
+//! # let mut state = State::new();
+//! # let mut context = Context::new(&mut state);
+//! context.do_string("config = {name = 'server', ports = {80, 443}}").unwrap();
+//! let table = context.push_global("config");
+//! let config: Config = luaext::serde::from_lua(&mut context, &table).unwrap();
+//! assert_eq!(config.ports, vec![80, 443]);
+//!
+//! let value = luaext::serde::to_lua(&mut context, &config).unwrap();
+//! context.set_global("copy", &value);
+//! # }
+//! ```
+
+use lua::{State, Index, Type, ffi};
+use serde_lib::ser::{self, Serialize};
+use serde_lib::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, Visitor, IntoDeserializer};
+use std::fmt;
+use std::slice;
+use std::str;
+use context::Context;
+use types::{LuaGeneric, LuaStackable};
+use error;
+
+/// Convert a value to Lua, and push it onto the stack.
+///
+/// # Errors
+///
+/// Returns an error if the value can not be represented in Lua, such as a map with a nil key or
+/// an integer that does not fit in an i64. Nothing is pushed if an error is returned.
+pub fn to_lua<T: Serialize + ?Sized>(context: &mut Context, value: &T)
+        -> error::Result<LuaGeneric> {
+    let top = context.get_state().get_top();
+    match value.serialize(Serializer { state: context.get_state() }) {
+        Ok(()) => error::new_luaresult_ok(context.handle(-1)),
+        Err(err) => {
+            context.get_state().set_top(top);
+            error::new_luaresult_err(error::LuaErrorType::TypeError, err.to_string())
+        }
+    }
+}
+
+/// Convert a value on the stack to a Rust value.
+///
+/// # Errors
+///
+/// Returns an error if the value does not match the structure of T. The error message includes
+/// the path of keys to the value that could not be converted, such as `servers[2].port`.
+pub fn from_lua<T: DeserializeOwned>(context: &mut Context, value: &dyn LuaStackable)
+        -> error::Result<T> {
+    let index = context.abs_index(value.get_pos());
+    match T::deserialize(Deserializer { state: context.get_state(), index }) {
+        Ok(value) => error::new_luaresult_ok(value),
+        Err(err) => error::new_luaresult_err(error::LuaErrorType::TypeError, err.to_string()),
+    }
+}
+
+/// An error that occurred while serializing or deserializing.
+#[derive(Debug)]
+struct Error {
+    /// Keys from the value that failed outwards
+    path: Vec<String>,
+    message: String,
+}
+
+impl Error {
+    fn new<T: fmt::Display>(message: T) -> Error {
+        Error {
+            path: Vec::new(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Record that this error occurred in the value stored under the given key.
+    fn within(mut self, key: String) -> Error {
+        self.path.push(key);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        let mut path = String::new();
+        for key in self.path.iter().rev() {
+            if !path.is_empty() && !key.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(key);
+        }
+        write!(f, "{} (at {})", self.message, path)
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(msg)
+    }
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+fn reserve(state: &mut State, n: Index) -> Result<()> {
+    if state.check_stack(n) {
+        Ok(())
+    } else {
+        Err(Error::new("stack overflow (value is nested too deeply)"))
+    }
+}
+
+fn push_bytes(state: &mut State, bytes: &[u8]) {
+    unsafe {
+        ffi::lua_pushlstring(state.as_ptr(), bytes.as_ptr() as *const _, bytes.len());
+    }
+}
+
+fn read_bytes(state: &mut State, index: Index) -> &[u8] {
+    let mut len = 0;
+    unsafe {
+        let ptr = ffi::lua_tolstring(state.as_ptr(), index, &mut len);
+        slice::from_raw_parts(ptr as *const u8, len)
+    }
+}
+
+/// Serializes a value by pushing it onto the stack.
+struct Serializer<'a> {
+    state: &'a mut State,
+}
+
+/// Serializes the entries of a table that is on top of the stack.
+struct SerializeTable<'a> {
+    state: &'a mut State,
+    /// The next array index, for sequences
+    next: i64,
+    /// Whether the table is stored in an enclosing table under the variant's name
+    variant: bool,
+}
+
+impl<'a> Serializer<'a> {
+    fn push_table(self, len: usize, array: bool, variant: Option<&'static str>)
+            -> Result<SerializeTable<'a>> {
+        reserve(self.state, 3)?;
+        if let Some(name) = variant {
+            self.state.create_table(0, 1);
+            self.state.push_string(name);
+        }
+        if array {
+            self.state.create_table(len as i32, 0);
+        } else {
+            self.state.create_table(0, len as i32);
+        }
+        Ok(SerializeTable {
+            state: self.state,
+            next: 1,
+            variant: variant.is_some(),
+        })
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_integer(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        if v > i64::MAX as u64 {
+            return Err(Error::new(format!("integer {} is too large for Lua", v)));
+        }
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_number(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_string(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        reserve(self.state, 1)?;
+        push_bytes(self.state, v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_nil();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str)
+            -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T)
+            -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32,
+            variant: &'static str, value: &T) -> Result<()> {
+        reserve(self.state, 3)?;
+        self.state.create_table(0, 1);
+        self.state.push_string(variant);
+        value.serialize(Serializer { state: self.state })?;
+        self.state.raw_set(-3);
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeTable<'a>> {
+        self.push_table(len.unwrap_or(0), true, None)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeTable<'a>> {
+        self.push_table(len, true, None)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize)
+            -> Result<SerializeTable<'a>> {
+        self.push_table(len, true, None)
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str,
+            len: usize) -> Result<SerializeTable<'a>> {
+        self.push_table(len, true, Some(variant))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable<'a>> {
+        self.push_table(len.unwrap_or(0), false, None)
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<SerializeTable<'a>> {
+        self.push_table(len, false, None)
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str,
+            len: usize) -> Result<SerializeTable<'a>> {
+        self.push_table(len, false, Some(variant))
+    }
+}
+
+impl<'a> SerializeTable<'a> {
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(Serializer { state: self.state })?;
+        self.state.raw_seti(-2, self.next);
+        self.next += 1;
+        Ok(())
+    }
+
+    fn push_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+            -> Result<()> {
+        reserve(self.state, 1)?;
+        self.state.push_string(key);
+        value.serialize(Serializer { state: self.state })?;
+        self.state.raw_set(-3);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.variant {
+            self.state.raw_set(-3);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        key.serialize(Serializer { state: self.state })?;
+        let invalid = match self.state.type_of(-1) {
+            Some(Type::Nil) => true,
+            Some(Type::Number) => self.state.to_number(-1).is_nan(),
+            _ => false,
+        };
+        if invalid {
+            self.state.pop(1);
+            return Err(Error::new("table keys can not be nil or NaN"));
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(Serializer { state: self.state })?;
+        self.state.raw_set(-3);
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+            -> Result<()> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+            -> Result<()> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Deserializes the value at an absolute stack index.
+struct Deserializer<'a> {
+    state: &'a mut State,
+    index: Index,
+}
+
+impl<'a> Deserializer<'a> {
+    fn unexpected(&mut self) -> Error {
+        Error::new(format!("can not convert a {} value", self.state.typename_at(self.index)))
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
+        match self.state.type_of(self.index) {
+            Some(Type::Nil) | Some(Type::None) => visitor.visit_unit(),
+            Some(Type::Boolean) => visitor.visit_bool(self.state.to_bool(self.index)),
+            Some(Type::Number) => {
+                if self.state.is_integer(self.index) {
+                    visitor.visit_i64(self.state.to_integer(self.index))
+                } else {
+                    visitor.visit_f64(self.state.to_number(self.index))
+                }
+            },
+            Some(Type::String) => {
+                let bytes = read_bytes(self.state, self.index);
+                match str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_str(s),
+                    Err(_) => visitor.visit_bytes(bytes),
+                }
+            },
+            Some(Type::Table) => {
+                if self.state.raw_len(self.index) > 0 {
+                    self.deserialize_seq(visitor)
+                } else {
+                    self.deserialize_map(visitor)
+                }
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.state.is_none_or_nil(self.index) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V)
+            -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
+        if !self.state.is_table(self.index) {
+            return Err(self.unexpected());
+        }
+        let len = self.state.raw_len(self.index) as i64;
+        visitor.visit_seq(SeqAccess {
+            state: self.state,
+            table: self.index,
+            next: 1,
+            len,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, _: usize, visitor: V)
+            -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value> {
+        if !self.state.is_table(self.index) {
+            return Err(self.unexpected());
+        }
+        reserve(self.state, 3)?;
+        let top = self.state.get_top();
+        self.state.push_nil();
+        let result = visitor.visit_map(MapAccess {
+            state: self.state,
+            table: self.index,
+            key: None,
+            done: false,
+        });
+        // the last key is left on the stack if the visitor stopped early
+        self.state.set_top(top);
+        result
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+            visitor: V) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(mut self, _: &'static str,
+            _: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        match self.state.type_of(self.index) {
+            Some(Type::String) => {
+                let name = String::from_utf8_lossy(read_bytes(self.state, self.index))
+                    .into_owned();
+                visitor.visit_enum(name.into_deserializer())
+            },
+            Some(Type::Table) => {
+                reserve(self.state, 4)?;
+                let top = self.state.get_top();
+                self.state.push_nil();
+                if !self.state.next(self.index) {
+                    return Err(Error::new("expected a table with a single variant, found an \
+                        empty table"));
+                }
+                if !self.state.is_string(top + 1) {
+                    self.state.set_top(top);
+                    return Err(Error::new("expected a variant name"));
+                }
+                let name = String::from_utf8_lossy(read_bytes(self.state, top + 1)).into_owned();
+                self.state.push_value(top + 1);
+                if self.state.next(self.index) {
+                    self.state.set_top(top);
+                    return Err(Error::new("expected a table with a single variant, found \
+                        several fields"));
+                }
+                let result = visitor.visit_enum(EnumAccess {
+                    state: self.state,
+                    index: top + 2,
+                    name: name.clone(),
+                }).map_err(|err| err.within(name));
+                self.state.set_top(top);
+                result
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    serde_lib::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+/// Reads the elements of an array table.
+struct SeqAccess<'a> {
+    state: &'a mut State,
+    table: Index,
+    next: i64,
+    len: i64,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for SeqAccess<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T)
+            -> Result<Option<T::Value>> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+        reserve(self.state, 1)?;
+        let i = self.next;
+        self.next += 1;
+        self.state.raw_geti(self.table, i);
+        let index = self.state.get_top();
+        let result = seed.deserialize(Deserializer { state: self.state, index });
+        self.state.pop(1);
+        result.map(Some).map_err(|err| err.within(format!("[{}]", i)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.next + 1) as usize)
+    }
+}
+
+/// Reads the entries of a table. The current key is kept on top of the stack between calls.
+struct MapAccess<'a> {
+    state: &'a mut State,
+    table: Index,
+    /// The current key, for error messages
+    key: Option<String>,
+    done: bool,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for MapAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.state.next(self.table) {
+            self.done = true;
+            return Ok(None);
+        }
+        // only the key is needed until next_value_seed is called
+        self.state.pop(1);
+        let index = self.state.get_top();
+        self.key = Some(match self.state.type_of(index) {
+            Some(Type::String) => String::from_utf8_lossy(read_bytes(self.state, index))
+                .into_owned(),
+            Some(Type::Number) if self.state.is_integer(index) => {
+                format!("[{}]", self.state.to_integer(index))
+            },
+            _ => format!("[{}]", self.state.typename_at(index)),
+        });
+        seed.deserialize(Deserializer { state: self.state, index }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        reserve(self.state, 2)?;
+        let key = self.state.get_top();
+        self.state.push_value(key);
+        self.state.raw_get(self.table);
+        let index = self.state.get_top();
+        let result = seed.deserialize(Deserializer { state: self.state, index });
+        self.state.pop(1);
+        let key = self.key.take();
+        result.map_err(|err| match key {
+            Some(key) => err.within(key),
+            None => err,
+        })
+    }
+}
+
+/// Reads an enum stored as a table with a single field.
+struct EnumAccess<'a> {
+    state: &'a mut State,
+    index: Index,
+    name: String,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = Deserializer<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V)
+            -> Result<(V::Value, Deserializer<'a>)> {
+        let name: de::value::StringDeserializer<Error> = self.name.into_deserializer();
+        let value = seed.deserialize(name)?;
+        Ok((value, Deserializer { state: self.state, index: self.index }))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V)
+            -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
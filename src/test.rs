@@ -1,7 +1,17 @@
 #![cfg(test)]
 use context::Context;
 use lua::{State, Type};
-use types::LuaFunction;
+use types::{self, LuaFunction, LuaGeneric, LuaInteger, LuaStackable, LuaTable, LuaVariant, LuaUserdata,
+    LuaLightUserdata, RegistryKey};
+use runtime::Lua;
+use error::{LuaErrorType, set_panic_handler};
+use libs::LuaLibs;
+use multi::Variadic;
+use value::LuaValue;
+use convert::{self, Convert, U64Bits};
+use userdata::LuaUserData;
+use class::{LuaClass, MetaMethod};
+use error;
 
 #[test]
 fn test_thread() {
@@ -52,7 +62,7 @@ fn test_thread() {
                 .get_value::<LuaFunction>(&mut context1).unwrap();
             let result = func.call_singleret(&mut context1, &[]).unwrap()
                 .get_value::<f64>(&mut context1);
-            return result.unwrap();
+            result.unwrap()
         });
         let thread2 = thread::spawn(move || {
             let mut context2 = Context::new(&mut state2);
@@ -60,7 +70,7 @@ fn test_thread() {
                 .get_value::<LuaFunction>(&mut context2).unwrap();
             let result = func.call_singleret(&mut context2, &[]).unwrap()
                 .get_value::<f64>(&mut context2);
-            return result.unwrap();
+            result.unwrap()
         });
 
         println!("Mean: {:?}", thread1.join().unwrap());
@@ -148,6 +158,7 @@ fn test_userdata() {
             self.value
         }
     }
+    impl LuaUserData for Foo {}
 
     let mut state = State::new();
     let mut context = Context::new(&mut state);
@@ -175,3 +186,1040 @@ fn test_stack_as_variable() {
 
     assert_eq!(context.push_global("foo").get_value(&mut context), Some(15));
 }
+
+#[test]
+fn test_runtime() {
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    context.do_string("foo = string.rep('a', 3)").unwrap();
+    assert_eq!(Some("aaa".to_string()), context.push_global("foo").get_value(&mut context));
+}
+
+#[test]
+fn test_runtime_memory_limit() {
+    let mut lua = Lua::builder()
+        .memory_limit(1024 * 1024)
+        .build();
+    assert!(lua.used_memory() <= 1024 * 1024);
+    let mut context = lua.context();
+    let result = context.do_string("
+        local t = {}
+        for i = 1, 1000000 do
+            t[i] = tostring(i)
+        end
+    ");
+    match result {
+        Err(err) => match err.get_type() {
+            LuaErrorType::MemoryError => {},
+            other => panic!("Expected a memory error, got {:?}", other),
+        },
+        Ok(_) => panic!("Expected the memory limit to be exceeded"),
+    }
+}
+
+#[test]
+fn test_runtime_sandbox() {
+    let mut lua = Lua::builder()
+        .sandbox(true)
+        .build();
+    let mut context = lua.context();
+    assert_eq!(context.push_global("io").type_of(&mut context), Type::Nil);
+    assert_eq!(context.push_global("load").type_of(&mut context), Type::Nil);
+    assert_eq!(context.push_global("math").type_of(&mut context), Type::Table);
+}
+
+#[test]
+fn test_runtime_send() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut lua = Lua::new();
+    {
+        let mut context = lua.context();
+        let counted = calls.clone();
+        let count = context.push_closure(move |_| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        });
+        context.set_global("count", &count);
+    }
+    let mut lua = thread::spawn(move || {
+        lua.context().do_string("count() count()").unwrap();
+        lua
+    }).join().unwrap();
+    lua.context().do_string("count()").unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_open_libs() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.open_libs(LuaLibs::BASE | LuaLibs::STRING);
+    assert_eq!(context.push_global("string").type_of(&mut context), Type::Table);
+    assert_eq!(context.push_global("print").type_of(&mut context), Type::Function);
+    assert_eq!(context.push_global("io").type_of(&mut context), Type::Nil);
+    assert_eq!(context.push_global("os").type_of(&mut context), Type::Nil);
+}
+
+#[test]
+#[should_panic]
+fn test_panic_handler() {
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    context.do_string("setmetatable(_G, {__newindex = function() error('no globals') end})")
+        .unwrap();
+    context.set_global("foo", &1);
+}
+
+#[test]
+fn test_panic_handler_payload() {
+    use std::panic;
+    use error::LuaError;
+    let result = panic::catch_unwind(|| {
+        let mut state = State::new();
+        state.open_libs();
+        set_panic_handler(&mut state);
+        let mut context = Context::new(&mut state);
+        context.do_string("setmetatable(_G, {__newindex = function() error('no globals', 0) end})")
+            .unwrap();
+        context.set_global("foo", &1);
+    });
+    let payload = result.unwrap_err();
+    let err = payload.downcast_ref::<LuaError>().unwrap();
+    assert_eq!(err.get_message(), "no globals");
+}
+
+#[test]
+fn test_panic_handler_message() {
+    use std::env;
+    use std::process::Command;
+    // the panic function aborts, so the error is raised in a child process running this test
+    if env::var_os("LUAEXT_RAISE_UNPROTECTED").is_some() {
+        let mut lua = Lua::new();
+        let mut context = lua.context();
+        let state = context.get_state();
+        state.push_string("raised outside of a protected call");
+        state.error();
+    }
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "test::test_panic_handler_message", "--nocapture"])
+        .env("LUAEXT_RAISE_UNPROTECTED", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("PANIC: unprotected error in call to Lua API \
+        (raised outside of a protected call)"), "{}", stderr);
+}
+
+#[test]
+fn test_stack_exhaustion() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    let mut result = Ok(());
+    for i in 0..1000000 {
+        if let Err(err) = context.try_push_integer(i) {
+            result = Err(err);
+            break;
+        }
+    }
+    match result {
+        Err(err) => match err.get_type() {
+            LuaErrorType::StackError => {},
+            other => panic!("Expected a stack error, got {:?}", other),
+        },
+        Ok(_) => panic!("Expected the stack to be exhausted"),
+    }
+}
+
+#[test]
+fn test_absolute_index() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.push_integer(1);
+    context.push_integer(2);
+    let generic = context.get_arg(-1).unwrap();
+    let integer: LuaInteger = context.get_arg_typed(-2).unwrap();
+    let made = LuaInteger::new(&mut context, -1);
+    context.push_string("foo");
+
+    assert_eq!(made.get_pos(), 2);
+    assert_eq!(made.get(&mut context), 2);
+
+    assert_eq!(generic.get_pos(), 2);
+    assert_eq!(Some(2), generic.get_value(&mut context));
+    assert_eq!(integer.get_pos(), 1);
+    assert_eq!(integer.get(&mut context), 1);
+}
+
+#[test]
+fn test_registry() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    let registry = context.get_registry();
+    registry.set(&mut context, &"luaext_test", &5);
+    assert_eq!(Some(5), context.get_from_registry_typed(&"luaext_test"));
+    assert_eq!(Some(5), registry.get_typed(&mut context, &"luaext_test"));
+}
+
+#[test]
+#[cfg(feature = "checked-handles")]
+#[should_panic(expected = "went out of scope")]
+fn test_checked_handle_scope() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    let value;
+    {
+        let mut new_context = context.push_context();
+        value = new_context.push_integer(5);
+    }
+    context.push_integer(16);
+    context.set_global("foo", &value);
+}
+
+#[test]
+#[cfg(feature = "checked-handles")]
+#[should_panic(expected = "different Lua state")]
+fn test_checked_handle_state() {
+    let mut state_foo = State::new();
+    let mut context_foo = Context::new(&mut state_foo);
+    let mut state_bar = State::new();
+    let mut context_bar = Context::new(&mut state_bar);
+    context_foo.push_integer(1);
+    let value_bar = context_bar.push_integer(2);
+    value_bar.get(&mut context_foo);
+}
+
+#[test]
+#[cfg(feature = "checked-handles")]
+#[should_panic(expected = "above the top of the stack")]
+fn test_checked_handle_new() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.push_integer(1);
+    // handles made with new are checked too
+    let value = LuaInteger::new(&mut context, 2);
+    value.get(&mut context);
+}
+
+#[test]
+fn test_guarded_function() {
+    fn unbalanced(state: &mut State) -> i32 {
+        let mut context = Context::new(state);
+        context.push_integer(1);
+        context.push_integer(2);
+        // returns one value, but Context::drop pops both
+        1
+    }
+    fn balanced(state: &mut State) -> i32 {
+        let context = Context::new(state);
+        context.return_context(&[&1, &2])
+    }
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    context.set_global("balanced", &lua_func_guarded!(balanced));
+    context.set_global("unbalanced", &lua_func_guarded!(unbalanced));
+    context.do_string("a, b = balanced(1)").unwrap();
+    assert_eq!(Some(2), context.push_global("b").get_value(&mut context));
+
+    if cfg!(debug_assertions) {
+        let err = context.do_string("unbalanced()").unwrap_err();
+        assert!(err.get_message().contains("'unbalanced'"), "{}", err.get_message());
+    }
+}
+
+#[test]
+fn test_dump_stack() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.push_nil();
+    context.push_bool(true);
+    context.push_number(1.5);
+    context.push_table();
+    let dump = context.dump_stack();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], "1: nil");
+    assert_eq!(lines[1], "2: boolean true");
+    assert_eq!(lines[2], "3: number 1.5");
+    assert!(lines[3].starts_with("4: table 0x"));
+}
+
+#[test]
+fn test_varargs() {
+    fn join(state: &mut State) -> i32 {
+        let mut context = Context::new(state);
+        let separator: String = context.get_arg_typed(1).unwrap();
+        let count = context.arg_count();
+        let parts: Vec<String> = context.rest(2).unwrap();
+        assert_eq!(parts.len() as i32, count - 1);
+        context.return_context(&[&parts.join(&separator)])
+    }
+    fn count_nils(state: &mut State) -> i32 {
+        let mut context = Context::new(state);
+        let count = context.args()
+            .filter(|arg| arg.type_of(&mut context) == Type::Nil)
+            .count();
+        context.return_context(&[&(count as i64)])
+    }
+
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.set_global("join", &lua_func_guarded!(join));
+    context.set_global("count_nils", &lua_func_guarded!(count_nils));
+    context.do_string("foo = join(', ', 'a', 'b', 'c')").unwrap();
+    context.do_string("bar = join(', ')").unwrap();
+    context.do_string("baz = count_nils(nil, 1, nil, 'a', nil)").unwrap();
+    assert_eq!(Some("a, b, c".to_string()), context.push_global("foo").get_value(&mut context));
+    assert_eq!(Some("".to_string()), context.push_global("bar").get_value(&mut context));
+    assert_eq!(Some(3), context.push_global("baz").get_value(&mut context));
+}
+
+#[test]
+fn test_multi_values() {
+    fn sum(state: &mut State) -> i32 {
+        let mut context = Context::new(state);
+        let Variadic(values): Variadic<f64> = context.get_args().unwrap();
+        let total: f64 = values.iter().sum();
+        context.return_values((total, values.len() as i64))
+    }
+
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.set_global("sum", &lua_func_guarded!(sum));
+    context.do_string("function pair(a, b) return a * 2, b .. '!' end").unwrap();
+    let sum: LuaFunction = context.push_global("sum").get_value(&mut context).unwrap();
+    let result: (f64, i64) = sum.pcall_typed(&mut context, (1.5, 2.5, 3.0)).unwrap();
+    assert_eq!(result, (7.0, 3));
+    let pair: LuaFunction = context.push_global("pair").get_value(&mut context).unwrap();
+    let result: (i64, String) = pair.pcall_typed(&mut context, (21, "hi")).unwrap();
+    assert_eq!(result, (42, "hi!".to_string()));
+    let result: Vec<f64> = sum.pcall_typed(&mut context, Variadic(vec![1, 2])).unwrap();
+    assert_eq!(result, vec![3.0, 2.0]);
+    // wrong arity and wrong types are both reported as type errors
+    let err = pair.pcall_typed::<_, (i64,)>(&mut context, (1, 2)).unwrap_err();
+    assert!(matches!(err.get_type(), LuaErrorType::TypeError));
+    let err = pair.pcall_typed::<_, (String, i64)>(&mut context, (1, 2)).unwrap_err();
+    assert!(err.get_message().contains("value #2"));
+}
+
+#[test]
+fn test_multi_missing_values() {
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    context.do_string("function one() return 1 end").unwrap();
+    let one: LuaFunction = context.push_global("one").get_value(&mut context).unwrap();
+    // missing trailing values are nil, so optional values become None
+    let (a, Convert(b)): (i64, Convert<Option<String>>) = one.pcall_typed(&mut context, ())
+        .unwrap();
+    assert_eq!((a, b), (1, None));
+    let err = one.pcall_typed::<_, (i64, i64)>(&mut context, ()).unwrap_err();
+    assert_eq!(err.get_message(), "expected 2 values, got 1");
+    // values pushed above the arguments are not mistaken for missing ones
+    let second = context.push_closure(|mut context| {
+        context.push_integer(99);
+        let (_, Convert(second)): (i64, Convert<Option<i64>>) = context.get_args()?;
+        Ok(context.return_values((second.is_none(),)))
+    });
+    let (missing,): (bool,) = second.pcall_typed(&mut context, (1,)).unwrap();
+    assert!(missing);
+}
+
+#[test]
+fn test_value_snapshot() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.open_libs(LuaLibs::BASE);
+    context.do_string("data = {10, 20, name = 'foo', nested = {flag = true}, f = print}").unwrap();
+    let data = {
+        let mut context = context.push_context();
+        let table = context.push_global("data");
+        LuaValue::snapshot(&mut context, table.get_pos()).unwrap()
+    };
+    match data {
+        LuaValue::Table(ref entries) => {
+            assert_eq!(entries[0], (LuaValue::Integer(1), LuaValue::Integer(10)));
+            assert_eq!(entries[1], (LuaValue::Integer(2), LuaValue::Integer(20)));
+            assert!(entries.contains(&(LuaValue::String(b"name".to_vec()),
+                LuaValue::String(b"foo".to_vec()))));
+        },
+        _ => panic!("expected a table"),
+    }
+    context.do_string("data = nil; collectgarbage()").unwrap();
+    context.set_global("copy", &data);
+    context.do_string("assert(copy[2] == 20 and copy.nested.flag and copy.f == print)").unwrap();
+    data.release(&mut context);
+
+    context.do_string("cycle = {}; cycle.inner = {outer = cycle}").unwrap();
+    let cycle = context.push_global("cycle");
+    let err = LuaValue::snapshot(&mut context, cycle.get_pos()).unwrap_err();
+    assert!(matches!(err.get_type(), LuaErrorType::TypeError));
+    assert_eq!(None, cycle.get_value::<LuaValue>(&mut context));
+}
+
+#[test]
+fn test_value_release() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    struct Tracked(Rc<Cell<bool>>);
+    impl LuaUserData for Tracked {}
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    let dropped = Rc::new(Cell::new(false));
+    let mut lua = Lua::new();
+    let value = {
+        let mut context = lua.context();
+        let value = {
+            let mut context = context.push_context();
+            let data = context.push_userdata(Tracked(dropped.clone()));
+            context.set_global("data", &data);
+            // a table that contains itself fails part of the way, after referencing the userdata
+            context.do_string("cycle = {data}; cycle.self = cycle").unwrap();
+            let cycle = context.push_global("cycle");
+            assert!(LuaValue::snapshot(&mut context, cycle.get_pos()).is_err());
+            LuaValue::snapshot(&mut context, data.get_pos()).unwrap()
+        };
+        context.do_string("data = nil; cycle = nil; collectgarbage()").unwrap();
+        assert!(!dropped.get());
+        drop(value);
+        context.do_string("collectgarbage()").unwrap();
+        assert!(dropped.get());
+
+        context.do_string("function f() end").unwrap();
+        context.push_global("f").get_value::<LuaValue>(&mut context).unwrap()
+    };
+    // references that outlive their state are not released
+    drop(lua);
+    drop(value);
+
+    let a = LuaValue::Table(vec![(LuaValue::Integer(1), LuaValue::Boolean(true)),
+        (LuaValue::String(b"x".to_vec()), LuaValue::Nil)]);
+    let b = LuaValue::Table(vec![(LuaValue::String(b"x".to_vec()), LuaValue::Nil),
+        (LuaValue::Integer(1), LuaValue::Boolean(true))]);
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_classify() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.do_string("values = {1, 2.0, 'three', {}, true}").unwrap();
+    let values = context.push_global("values").get_value::<LuaTable>(&mut context).unwrap();
+    let mut kinds = Vec::new();
+    for i in 1..6 {
+        let value = values.get(&mut context, &(i as i64));
+        kinds.push(match value.classify(&mut context) {
+            LuaVariant::Integer(n) => format!("integer {}", n.get(&mut context)),
+            LuaVariant::Number(n) => format!("number {}", n.get(&mut context)),
+            LuaVariant::String(s) => format!("string {}", s.get(&mut context)),
+            LuaVariant::Table(_) => "table".to_string(),
+            LuaVariant::Bool(b) => format!("bool {}", b.get(&mut context)),
+            _ => "other".to_string(),
+        });
+    }
+    assert_eq!(kinds, ["integer 1", "number 2", "string three", "table", "bool true"]);
+
+    // a closure has only one upvalue of its own, so the second one refers to no value
+    let check = context.push_closure(|mut context| {
+        let missing = LuaGeneric::new(&mut context, types::upvalue_index(2));
+        assert!(matches!(missing.classify(&mut context), LuaVariant::None));
+        Ok(0)
+    });
+    check.call_noret(&mut context, &[]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use serde_lib::{Serialize, Deserialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Mode {
+        Fast,
+        Limited(u32),
+        Custom { level: i8 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Server {
+        host: String,
+        port: u16,
+        modes: Vec<Mode>,
+        tags: BTreeMap<String, bool>,
+        backup: Option<Box<Server>>,
+    }
+
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.do_string("server = {host = 'a', port = 80, modes = {'Fast', {Limited = 3}, \
+        {Custom = {level = -1}}}, tags = {x = true}, backup = {host = 'b', port = 81, \
+        modes = {}, tags = {}}}").unwrap();
+    let table = context.push_global("server");
+    let server: Server = ::serde::from_lua(&mut context, &table).unwrap();
+    assert_eq!(server.modes, [Mode::Fast, Mode::Limited(3), Mode::Custom { level: -1 }]);
+    assert_eq!(server.backup.as_ref().unwrap().port, 81);
+
+    let top = context.get_state().get_top();
+    let value = ::serde::to_lua(&mut context, &server).unwrap();
+    assert_eq!(context.get_state().get_top(), top + 1);
+    let copy: Server = ::serde::from_lua(&mut context, &value).unwrap();
+    assert_eq!(copy, server);
+    assert_eq!(context.get_state().get_top(), top + 1);
+
+    context.do_string("server.backup.modes = {'Fast', {Limited = 'x'}}").unwrap();
+    let err = ::serde::from_lua::<Server>(&mut context, &table).unwrap_err();
+    assert!(err.get_message().ends_with("(at backup.modes[2].Limited)"), "{}", err);
+    context.do_string("server.backup = nil; server.port = 70000").unwrap();
+    let err = ::serde::from_lua::<Server>(&mut context, &table).unwrap_err();
+    assert!(err.get_message().ends_with("(at port)"), "{}", err);
+}
+
+#[test]
+fn test_convert_collections() {
+    use std::collections::{HashMap, BTreeSet};
+    use std::borrow::Cow;
+
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    let mut scores = HashMap::new();
+    scores.insert("alice".to_string(), vec![1i64, 2]);
+    scores.insert("bob".to_string(), vec![]);
+    context.set_global("scores", &Convert(&scores));
+    context.set_global("pair", &Convert((Cow::Borrowed("x"), Some(1.5))));
+    context.set_global("flags", &Convert(["a", "b"].iter().collect::<BTreeSet<_>>()));
+    context.do_string("total = #scores.alice + #scores.bob; second = pair[2]; \
+        has_b = flags.b; grid = {{1, 2}, {3, 4}}").unwrap();
+    assert_eq!(Some(2), context.push_global("total").get_value::<i64>(&mut context));
+    assert_eq!(Some(1.5), context.push_global("second").get_value::<f64>(&mut context));
+    assert_eq!(Some(true), context.push_global("has_b").get_value::<bool>(&mut context));
+
+    let copy = context.push_global("scores")
+        .get_value::<Convert<HashMap<String, Vec<i64>>>>(&mut context);
+    assert_eq!(copy, Some(Convert(scores)));
+    let grid = context.push_global("grid")
+        .get_value::<Convert<[Box<[i64; 2]>; 2]>>(&mut context);
+    assert_eq!(grid, Some(Convert([Box::new([1, 2]), Box::new([3, 4])])));
+    let flags = context.push_global("flags")
+        .get_value::<Convert<BTreeSet<String>>>(&mut context)
+        .map(Convert::into_inner);
+    assert_eq!(flags.map(|f| f.len()), Some(2));
+    // conversions that fail leave nothing behind on the stack
+    let top = context.get_state().get_top();
+    let wrong = context.push_global("grid")
+        .get_value::<Convert<Vec<(i64, Option<String>)>>>(&mut context);
+    assert!(wrong.is_none());
+    assert_eq!(context.get_state().get_top(), top + 1);
+}
+
+#[test]
+fn test_integer_widths() {
+    let mut state = State::new();
+    let mut context = Context::new(&mut state);
+    context.set_global("small", &Convert(200u8));
+    context.set_global("ids", &Convert(vec![U64Bits(u64::MAX), U64Bits(7)]));
+    context.do_string("big = small * 2; neg = -1; frac = 1.5; whole = 3.0; text = '12'").unwrap();
+    assert_eq!(Some(Convert(400u16)), context.push_global("big").get_value(&mut context));
+    assert!(context.push_global("big").get_value::<Convert<u8>>(&mut context).is_none());
+    assert!(context.push_global("neg").get_value::<Convert<u32>>(&mut context).is_none());
+    assert_eq!(Some(Convert(-1i8)), context.push_global("neg").get_value(&mut context));
+    assert_eq!(Some(Convert(3usize)), context.push_global("whole").get_value(&mut context));
+    assert_eq!(Some(Convert(12i128)), context.push_global("text").get_value(&mut context));
+    context.do_string("mixed = {2.0, '3'}").unwrap();
+    assert_eq!(Some(Convert(vec![2i32, 3])), context.push_global("mixed").get_value(&mut context));
+    assert_eq!(Some(Convert(vec![2i64, 3])), context.push_global("mixed").get_value(&mut context));
+
+    let frac = context.push_global("frac");
+    let err = convert::to_integer::<i32>(context.get_state(), frac.get_pos()).unwrap_err();
+    assert_eq!(err.get_message(), "number 1.5 has no integer representation");
+    let big = context.push_global("big");
+    let err = convert::to_integer::<u8>(context.get_state(), big.get_pos()).unwrap_err();
+    assert_eq!(err.get_message(), "integer 400 is out of range for u8");
+
+    let ids = context.push_global("ids")
+        .get_value::<Convert<Vec<U64Bits>>>(&mut context)
+        .map(Convert::into_inner);
+    assert_eq!(ids, Some(vec![U64Bits(u64::MAX), U64Bits(7)]));
+
+    let too_big = context.push_closure(|context| Ok(context.return_values((Convert(u64::MAX),))));
+    context.set_global("too_big", &too_big);
+    let err = context.do_string("too_big()").unwrap_err();
+    assert!(err.get_message().contains("out of range for a Lua integer"));
+    let top = context.get_state().get_top();
+    match convert::try_push(&mut context, &vec![1, u64::MAX]) {
+        Err(err) => assert!(matches!(err.get_type(), LuaErrorType::TypeError)),
+        Ok(_) => panic!("expected an out of range error"),
+    }
+    assert_eq!(context.get_state().get_top(), top);
+}
+
+#[test]
+fn test_typed_userdata() {
+    use std::rc::Rc;
+
+    struct Tracked(Rc<()>);
+    impl LuaUserData for Tracked {}
+    struct Other;
+    impl LuaUserData for Other {}
+
+    let tracker = Rc::new(());
+    let mut lua = Lua::new();
+    {
+        let mut context = lua.context();
+        let tracked = context.push_userdata(Tracked(tracker.clone()));
+        let other = context.push_userdata(Other);
+        assert!(tracked.is::<Tracked>(&mut context));
+        assert!(!other.is::<Tracked>(&mut context));
+        assert!(tracked.borrow::<Other>(&mut context).is_none());
+        assert_eq!(Rc::strong_count(&tracked.borrow::<Tracked>(&mut context).unwrap().0), 2);
+        context.set_global("tracked", &tracked);
+        context.do_string("name = getmetatable(tracked)").unwrap();
+        let name: Option<String> = context.push_global("name").get_value(&mut context);
+        assert!(name.as_ref().unwrap().ends_with("Tracked"), "{:?}", name);
+        context.do_string("tracked = nil").unwrap();
+    }
+    lua.context().do_string("collectgarbage()").unwrap();
+    assert_eq!(Rc::strong_count(&tracker), 1);
+}
+
+#[test]
+fn test_userdata_borrow() {
+    struct Account {
+        balance: i64,
+    }
+    impl LuaUserData for Account {}
+    struct Other;
+    impl LuaUserData for Other {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    let a = context.push_userdata(Account { balance: 100 });
+    context.set_global("a", &a);
+    // a conflict is raised as a Lua error once the closure has unwound, dropping its guards
+    let deposit = context.push_closure(|mut context| {
+        let account: LuaUserdata = context.get_arg_typed(1).unwrap();
+        let _shared = account.borrow::<Account>(&mut context).unwrap();
+        account.borrow_mut::<Account>(&mut context).unwrap().balance += 10;
+        Ok(0)
+    });
+    context.set_global("deposit", &deposit);
+    let err = context.do_string("deposit(a)").unwrap_err();
+    assert!(err.get_message().contains("userdata already borrowed"), "{}", err);
+    let deposit = context.push_closure(|mut context| {
+        let account: LuaUserdata = context.get_arg_typed(1).unwrap();
+        account.borrow_mut::<Account>(&mut context).unwrap().balance += 10;
+        Ok(0)
+    });
+    context.set_global("deposit", &deposit);
+    context.do_string("deposit(a)").unwrap();
+    {
+        let shared = a.borrow::<Account>(&mut context).unwrap();
+        let err = context.do_string("deposit(a)").unwrap_err();
+        assert!(err.get_message().contains("userdata already borrowed"));
+        let also_shared = a.try_borrow::<Account>(&mut context).unwrap();
+        assert_eq!(shared.balance + also_shared.balance, 220);
+    }
+    {
+        let _exclusive = a.borrow_mut::<Account>(&mut context).unwrap();
+        let err = a.try_borrow::<Account>(&mut context).err().unwrap();
+        assert_eq!(err.get_message(), "userdata already mutably borrowed");
+    }
+    context.do_string("deposit(a)").unwrap();
+    assert_eq!(a.borrow::<Account>(&mut context).unwrap().balance, 120);
+    assert!(matches!(a.try_borrow::<Other>(&mut context).err().unwrap().get_type(),
+        LuaErrorType::TypeError));
+}
+
+#[test]
+fn test_userdata_gc_while_borrowed() {
+    struct Resource {
+        value: i64,
+    }
+    impl LuaUserData for Resource {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Resource>::new("Resource")
+        .constructor("new", |_, (value,): (i64,)| Ok(Resource { value }))
+        .method("with", |context, _, (callback,): (LuaFunction,)| {
+            callback.call_noret(context, &[]);
+            Ok(())
+        })
+        .method("value", |_, this, ()| Ok((this.value,)))
+        .protect_metatable(false)
+        .register(&mut context);
+    context.do_string("
+        local r = Resource.new(3)
+        local ok, err = pcall(r.with, r, function() getmetatable(r).__gc(r) end)
+        assert(not ok and err:find('borrowed'), err)
+        assert(r:value() == 3)").unwrap();
+}
+
+#[test]
+fn test_class() {
+    #[derive(Clone, Copy)]
+    struct Vec2 {
+        x: f64,
+        y: f64,
+    }
+    impl LuaUserData for Vec2 {}
+
+    fn get(context: &mut Context, value: &LuaUserdata) -> error::Result<Vec2> {
+        value.try_borrow::<Vec2>(context).map(|v| *v)
+    }
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Vec2>::new("Vec2")
+        .constructor("new", |_, (x, y): (f64, f64)| Ok(Vec2 { x, y }))
+        .function("dot", |ctx, (a, b): (LuaUserdata, LuaUserdata)| {
+            let (a, b) = (get(ctx, &a)?, get(ctx, &b)?);
+            Ok((a.x * b.x + a.y * b.y,))
+        })
+        .method("length", |_, this, ()| Ok((this.x.hypot(this.y),)))
+        .method_mut("scale", |_, this, (n,): (f64,)| {
+            this.x *= n;
+            this.y *= n;
+            Ok(())
+        })
+        .method("fail", |_, _, ()| -> error::Result<()> { panic!("oops") })
+        .meta_function(MetaMethod::Add, |ctx, (a, b): (LuaUserdata, LuaUserdata)| {
+            let (a, b) = (get(ctx, &a)?, get(ctx, &b)?);
+            Ok((ctx.push_userdata(Vec2 { x: a.x + b.x, y: a.y + b.y }),))
+        })
+        .meta_method(MetaMethod::Eq, |ctx, this, (other,): (LuaUserdata,)| {
+            let other = get(ctx, &other)?;
+            Ok((this.x == other.x && this.y == other.y,))
+        })
+        .meta_method(MetaMethod::ToString, |_, this, ()| Ok((format!("({}, {})", this.x, this.y),)))
+        .register(&mut context);
+
+    context.do_string("
+        local a = Vec2.new(3, 4)
+        assert(a:length() == 5)
+        a:scale(2)
+        assert(tostring(a) == '(6, 8)')
+        local b = a + Vec2.new(1, 1)
+        assert(tostring(b) == '(7, 9)')
+        assert(b == Vec2.new(7, 9) and b ~= a)
+        assert(Vec2.dot(a, b) == 114)
+        assert(getmetatable(a) == 'Vec2')
+    ").unwrap();
+    let err = context.do_string("Vec2.new(1, 2):scale('x')").unwrap_err();
+    assert_eq!(err.get_message(),
+        "Vec2:scale: value #1 (a string value) can not be \
+        converted to f64");
+    let err = context.do_string("Vec2.new(1, 2).length(5)").unwrap_err();
+    assert!(err.get_message().ends_with("Vec2:length: expected a luaext::test::test_class::Vec2 \
+        as the first argument"), "{}", err);
+    let err = context.do_string("Vec2.new(1, 2):fail()").unwrap_err();
+    assert!(err.get_message().ends_with("panic in Rust function: oops"), "{}", err);
+}
+
+#[test]
+fn test_class_properties() {
+    struct Player {
+        name: String,
+        health: i64,
+    }
+    impl LuaUserData for Player {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Player>::new("Player")
+        .constructor("new", |_, (name,): (String,)| Ok(Player { name, health: 100 }))
+        .getter("name", |_, this| Ok(this.name.clone()))
+        .getter("health", |_, this| Ok(this.health))
+        .setter("health", |_, this, health: i64| {
+            this.health = health.max(0);
+            Ok(())
+        })
+        .method("is_alive", |_, this, ()| Ok((this.health > 0,)))
+        .register(&mut context);
+
+    context.do_string("
+        local p = Player.new('ann')
+        p.health = p.health - 150
+        assert(p.name == 'ann' and p.health == 0 and not p:is_alive())
+        assert(p.missing == nil)
+    ").unwrap();
+    let err = context.do_string("Player.new('bob').name = 'eve'").unwrap_err();
+    assert_eq!(err.get_message(), "Player.name is read-only");
+    let err = context.do_string("Player.new('bob').mana = 5").unwrap_err();
+    assert_eq!(err.get_message(), "Player has no property 'mana'");
+    let err = context.do_string("Player.new('bob').health = 'full'").unwrap_err();
+    assert_eq!(err.get_message(),
+        "Player.health: value #1 (a string value) can not be converted to i64");
+}
+
+#[test]
+fn test_class_derived_metamethods() {
+    use std::fmt;
+    use std::ops::{Add, Sub, Mul, Neg};
+
+    #[derive(Clone, PartialEq, PartialOrd)]
+    struct Meters(f64);
+    impl LuaUserData for Meters {
+        fn type_name() -> &'static str {
+            "Meters"
+        }
+    }
+    impl fmt::Display for Meters {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}m", self.0)
+        }
+    }
+    impl Add for Meters {
+        type Output = Meters;
+        fn add(self, other: Meters) -> Meters { Meters(self.0 + other.0) }
+    }
+    impl Sub for Meters {
+        type Output = Meters;
+        fn sub(self, other: Meters) -> Meters { Meters(self.0 - other.0) }
+    }
+    impl Mul for Meters {
+        type Output = Meters;
+        fn mul(self, other: Meters) -> Meters { Meters(self.0 * other.0) }
+    }
+    impl Neg for Meters {
+        type Output = Meters;
+        fn neg(self) -> Meters { Meters(-self.0) }
+    }
+    struct Feet;
+    impl LuaUserData for Feet {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Meters>::new("Meters")
+        .constructor("new", |_, (n,): (f64,)| Ok(Meters(n)))
+        .derive_display()
+        .derive_eq()
+        .derive_ord()
+        .derive_add()
+        .derive_sub()
+        .derive_mul()
+        .derive_neg()
+        .derive_clone()
+        .register(&mut context);
+    LuaClass::<Feet>::new("Feet")
+        .constructor("new", |_, ()| Ok(Feet))
+        .register(&mut context);
+
+    context.do_string("
+        assert(Feet.new() ~= Meters.new(2) and Meters.new(2) ~= Feet.new())
+        local a, b = Meters.new(2), Meters.new(3)
+        assert(tostring(a + b) == '5m')
+        assert(tostring(-(b - a) * b) == '-3m')
+        assert(a < b and a <= a:clone() and not (b < a))
+        assert(a == a:clone() and a ~= b)
+    ").unwrap();
+    let err = context.do_string("local x = Meters.new(1) + 1").unwrap_err();
+    assert_eq!(err.get_message(), "Meters.__add: expected a Meters, got a number value");
+}
+
+#[test]
+fn test_class_inheritance() {
+    struct Entity {
+        id: i64,
+    }
+    impl LuaUserData for Entity {}
+    struct Actor {
+        entity: Entity,
+        health: i64,
+    }
+    impl LuaUserData for Actor {}
+    struct Player {
+        actor: Actor,
+        name: String,
+    }
+    impl LuaUserData for Player {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Entity>::new("Entity")
+        .method("id", |_, this, ()| Ok((this.id,)))
+        .register(&mut context);
+    LuaClass::<Actor>::new("Actor")
+        .extends(|actor: &Actor| &actor.entity, |actor: &mut Actor| &mut actor.entity)
+        .method_mut("damage", |_, this, (n,): (i64,)| {
+            this.health -= n;
+            Ok((this.health,))
+        })
+        .register(&mut context);
+    LuaClass::<Player>::new("Player")
+        .extends(|player: &Player| &player.actor, |player: &mut Player| &mut player.actor)
+        .constructor("new", |_, (id, name): (i64, String)| {
+            Ok(Player { actor: Actor { entity: Entity { id }, health: 10 }, name })
+        })
+        .method("name", |_, this, ()| Ok((this.name.clone(),)))
+        .register(&mut context);
+
+    context.do_string("
+        player = Player.new(7, 'ann')
+        assert(player:name() == 'ann' and player:id() == 7)
+        assert(player:damage(3) == 7)
+    ").unwrap();
+    let player: LuaUserdata = context.push_global("player").get_value(&mut context).unwrap();
+    assert_eq!(player.borrow::<Actor>(&mut context).unwrap().health, 7);
+    assert_eq!(player.borrow::<Entity>(&mut context).unwrap().id, 7);
+    {
+        let _entity = player.borrow_mut::<Entity>(&mut context).unwrap();
+        let err = player.try_borrow::<Player>(&mut context).err().unwrap();
+        assert_eq!(err.get_message(), "userdata already mutably borrowed");
+    }
+    let entity = context.push_userdata(Entity { id: 1 });
+    assert!(entity.borrow::<Player>(&mut context).is_none());
+    context.set_global("entity", &entity);
+    let err = context.do_string("player.name(entity)").unwrap_err();
+    assert!(err.get_message().starts_with("Player:name: userdata is not a "), "{}", err);
+}
+
+#[test]
+fn test_class_info_copied() {
+    struct Base {
+        id: i64,
+    }
+    impl LuaUserData for Base {}
+    struct Derived {
+        base: Base,
+    }
+    impl LuaUserData for Derived {}
+    struct Other(u8);
+    impl LuaUserData for Other {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    LuaClass::<Base>::new("Base").register(&mut context);
+    LuaClass::<Derived>::new("Derived")
+        .extends(|derived: &Derived| &derived.base, |derived: &mut Derived| &mut derived.base)
+        .constructor("new", |_, (id,): (i64,)| Ok(Derived { base: Base { id } }))
+        .protect_metatable(false)
+        .register(&mut context);
+    LuaClass::<Other>::new("Other")
+        .constructor("new", |_, ()| Ok(Other(1)))
+        .protect_metatable(false)
+        .register(&mut context);
+    context.do_string("
+        derived = Derived.new(4)
+        other = Other.new()
+        getmetatable(other).__luaext_class = getmetatable(derived).__luaext_class").unwrap();
+    let derived: LuaUserdata = context.push_global("derived").get_value(&mut context).unwrap();
+    assert_eq!(derived.borrow::<Base>(&mut context).unwrap().id, 4);
+    let other: LuaUserdata = context.push_global("other").get_value(&mut context).unwrap();
+    assert!(other.borrow::<Base>(&mut context).is_none());
+    assert_eq!(other.borrow::<Other>(&mut context).unwrap().0, 1);
+}
+
+#[test]
+fn test_user_value_cycle() {
+    use std::rc::Rc;
+    use std::cell::Cell;
+
+    struct Node {
+        dropped: Rc<Cell<bool>>,
+    }
+    impl LuaUserData for Node {}
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    let dropped = Rc::new(Cell::new(false));
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    {
+        let mut context = context.push_context();
+        let node = context.push_userdata(Node { dropped: dropped.clone() });
+        assert!(node.get_user_value(&mut context).get_value::<bool>(&mut context).is_none());
+        node.set_user_value(&mut context, &true);
+        assert_eq!(node.get_user_value(&mut context).get_value::<bool>(&mut context), Some(true));
+        context.set_global("node", &node);
+        // the callback refers to the node that holds it
+        context.do_string("local n = node; callback = function() return n end").unwrap();
+        let callback = context.push_global("callback");
+        node.user_table(&mut context).set(&mut context, &"callback", &callback);
+        context.do_string("assert(callback() == node); callback = nil").unwrap();
+    }
+    context.do_string("collectgarbage()").unwrap();
+    assert!(!dropped.get());
+    context.do_string("node = nil; collectgarbage()").unwrap();
+    assert!(dropped.get());
+}
+
+#[test]
+fn test_userdata_alignment() {
+    #[repr(align(64))]
+    struct Aligned([f32; 8]);
+    impl LuaUserData for Aligned {}
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    for i in 0..8 {
+        let mut context = context.push_context();
+        // strings of different lengths in between, so that the blocks land at different offsets
+        context.push_string(&"x".repeat(i * 8));
+        let data = context.push_userdata(Aligned([i as f32; 8]));
+        {
+            let value = data.borrow::<Aligned>(&mut context).unwrap();
+            assert_eq!(&*value as *const Aligned as usize % 64, 0);
+            assert_eq!(value.0, [i as f32; 8]);
+        }
+        let raw = unsafe { data.get_value::<Aligned>(&mut context).unwrap() as *const Aligned };
+        assert_eq!(raw as usize % 64, 0);
+    }
+    context.do_string("collectgarbage()").unwrap();
+}
+
+#[test]
+fn test_light_userdata() {
+    static FIRST: RegistryKey<i64> = RegistryKey::new();
+    static SECOND: RegistryKey<i64> = RegistryKey::new();
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    let mut value = 5i32;
+    let ptr = &mut value as *mut i32;
+    let light = context.push_light_userdata(ptr);
+    assert_eq!(light.as_ptr(&mut context) as *mut i32, ptr);
+    context.set_global("light", &light);
+    let copy: LuaLightUserdata = context.push_global("light").get_value(&mut context).unwrap();
+    assert_eq!(copy.as_ptr(&mut context) as *mut i32, ptr);
+
+    assert_eq!(FIRST.get(&mut context), None);
+    FIRST.set(&mut context, &1);
+    SECOND.set(&mut context, &2);
+    assert_eq!(FIRST.get(&mut context), Some(1));
+    assert_eq!(SECOND.get(&mut context), Some(2));
+}
+
+#[test]
+fn test_protected_operations() {
+    use std::panic::{self, AssertUnwindSafe};
+    use error::LuaError;
+
+    let mut lua = Lua::new();
+    let mut context = lua.context();
+    context.do_string("t = setmetatable({}, {__index = function() error({}) end,
+        __len = function() error('no length', 0) end})").unwrap();
+    let table: LuaTable = context.push_global("t").get_value(&mut context).unwrap();
+    let top = context.get_state().get_top();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        table.get(&mut context, &"foo");
+    }));
+    let payload = result.unwrap_err();
+    let err = payload.downcast_ref::<LuaError>().unwrap();
+    assert_eq!(err.get_message(), "(error object is a table value)");
+    assert_eq!(context.get_state().get_top(), top);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| table.len(&mut context)));
+    let payload = result.unwrap_err();
+    assert_eq!(payload.downcast_ref::<LuaError>().unwrap().get_message(), "no length");
+}
@@ -1,22 +1,27 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
 use types::{LuaStackable};
 use context::Context;
 
 /// Represents a boolean value on the Lua stack
 pub struct LuaBool {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaBool {
-    /// Create a new LuaBool given an index
-    pub fn new(i: Index) -> LuaBool {
+    /// Create a new LuaBool at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaBool {
+        let state = context.get_state();
         LuaBool {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Get the value of this boolean
     pub fn get(&self, context: &mut Context) -> bool {
+        self.tag.verify(context.get_state(), self.index);
         context.get_state().to_bool(self.index)
     }
 }
@@ -29,6 +34,7 @@ impl LuaStackable for LuaBool {
 
 impl ToLua for LuaBool {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -36,7 +42,10 @@ impl ToLua for LuaBool {
 impl FromLua for LuaBool {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaBool> {
         if state.is_bool(index) {
-            Some(LuaBool::new(index))
+            Some(LuaBool {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
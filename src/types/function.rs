@@ -1,7 +1,10 @@
 use lua::{Index, ToLua, FromLua, State, MULTRET};
+use check::HandleTag;
 use types::{LuaStackable, LuaGeneric};
 use context::Context;
+use protect;
 use error;
+use multi::{ToLuaMulti, FromLuaMulti};
 
 /// Reperesents a callable function on the Lua stack
 ///
@@ -24,7 +27,8 @@ use error;
 /// assert_eq!(result, Some(8));
 /// ```
 pub struct LuaFunction {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 fn get_callback_index(val: &Option<&LuaFunction>) -> Index {
@@ -35,10 +39,12 @@ fn get_callback_index(val: &Option<&LuaFunction>) -> Index {
 }
 
 impl LuaFunction {
-    /// Create a new LuaFunction given an index
-    pub fn new(i: Index) -> LuaFunction {
+    /// Create a new LuaFunction at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaFunction {
+        let state = context.get_state();
         LuaFunction {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
@@ -53,8 +59,10 @@ impl LuaFunction {
     /// an argument, and the function should return a new error message.
     /// errfunc will not be called if no error was encountered, the error that occured was an
     /// out of memeory error, or if another error occurred while running errfunc.
-    pub fn pcall(&self, context: &mut Context, args: &[&ToLua], errfunc: Option<&LuaFunction>,
+    pub fn pcall(&self, context: &mut Context, args: &[&dyn ToLua], errfunc: Option<&LuaFunction>,
             nresults: i32) -> error::Result<Vec<LuaGeneric>> {
+        self.tag.verify(context.get_state(), self.index);
+        context.try_reserve(args.len() as i32 + 1)?;
         let top_prev = context.get_state().get_top();
         context.get_state().push_value(self.get_pos());
         for arg in args {
@@ -69,21 +77,23 @@ impl LuaFunction {
             Ok(_) => {
                 let top_post = context.get_state().get_top();
                 error::new_luaresult_ok((top_prev..top_post)
-                    .map(|i| LuaGeneric::new(i+1))
+                    .map(|i| context.handle(i+1))
                     .collect())
             }
         }
     }
 
     /// Same as pcall, but returns all return values
-    pub fn pcall_multiret(&self, context: &mut Context, args: &[&ToLua],
+    pub fn pcall_multiret(&self, context: &mut Context, args: &[&dyn ToLua],
             errfunc: Option<&LuaFunction>) -> error::Result<Vec<LuaGeneric>> {
+        self.tag.verify(context.get_state(), self.index);
         self.pcall(context, args, errfunc, MULTRET)
     }
 
     /// Same as pcall, but only returns at most one return value.
-    pub fn pcall_singleret(&self, context: &mut Context, args: &[&ToLua],
+    pub fn pcall_singleret(&self, context: &mut Context, args: &[&dyn ToLua],
             errfunc: Option<&LuaFunction>) -> error::Result<Option<LuaGeneric>> {
+        self.tag.verify(context.get_state(), self.index);
         self.pcall(context, args, errfunc, 1)
             .map(|mut v| {
                 match v.len() {
@@ -94,34 +104,77 @@ impl LuaFunction {
     }
 
     /// Same as pcall, but returns nothing.
-    pub fn pcall_noret(&self, context: &mut Context, args: &[&ToLua],
+    pub fn pcall_noret(&self, context: &mut Context, args: &[&dyn ToLua],
             errfunc: Option<&LuaFunction>) -> error::Result<()> {
+        self.tag.verify(context.get_state(), self.index);
         self.pcall(context, args, errfunc, 0)
             .map(|_|())
     }
 
+    /// Protected function call with typed arguments and return values.
+    ///
+    /// The arguments are pushed with ToLuaMulti, and every return value is converted with
+    /// FromLuaMulti. The return values stay on the stack until the context is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an Error if Lua encounters a runtime error while running this function, if the
+    /// number of return values does not match the arity of R, or if any return value can not be
+    /// converted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// # use luaext::types::function::LuaFunction;
+    /// # let mut state = State::new();
+    /// # let mut context = Context::new(&mut state);
+    /// context.do_string("function divmod(a, b) return a // b, a % b end").unwrap();
+    /// let divmod: LuaFunction = context.push_global("divmod")
+    ///     .get_value(&mut context).unwrap();
+    /// let result: (i64, i64) = divmod.pcall_typed(&mut context, (7, 2)).unwrap();
+    /// assert_eq!(result, (3, 1));
+    /// ```
+    pub fn pcall_typed<A, R>(&self, context: &mut Context, args: A) -> error::Result<R>
+            where A: ToLuaMulti, R: FromLuaMulti {
+        self.tag.verify(context.get_state(), self.index);
+        context.try_reserve(args.count() + 1)?;
+        let top_prev = context.get_state().get_top();
+        context.get_state().push_value(self.get_pos());
+        let nargs = args.to_lua_multi(context.get_state());
+        let threadstatus = context.get_state().pcall(nargs, MULTRET, 0);
+        if let Err(status) = error::get_status_from_threadstatus(threadstatus) {
+            return error::new_luaresult_err(status, error::pop_error_from_state(context.get_state()));
+        }
+        let top_post = context.get_state().get_top();
+        R::from_lua_multi(context.get_state(), top_prev + 1, top_post - top_prev)
+    }
+
     /// Call this function, returns a list of return values (with a maximum
     /// size nresults).
     ///
     /// # Panics
     ///
-    /// This function will panic if Lua encounters a runtime error. It's not really a panic, it's
-    /// actually a longjmp, but it might as well be a panic.
-    pub fn call(&self, context: &mut Context, args: &[&ToLua], nresults: i32) -> Vec<LuaGeneric> {
+    /// This function will panic with a `LuaError` payload if Lua encounters a runtime error.
+    pub fn call(&self, context: &mut Context, args: &[&dyn ToLua], nresults: i32) -> Vec<LuaGeneric> {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(args.len() as i32 + 2);
         let top_prev = context.get_state().get_top();
         context.get_state().push_value(self.get_pos());
         for arg in args {
             arg.to_lua(context.get_state());
         }
-        context.get_state().call(args.len() as i32, nresults);
+        protect::call_function(context.get_state(), args.len() as i32, nresults);
         let top_post = context.get_state().get_top();
         (top_prev..top_post)
-            .map(|i| LuaGeneric::new(i+1))
+            .map(|i| context.handle(i+1))
             .collect()
     }
 
     /// Same as call, but returns at most one return value.
-    pub fn call_singleret(&self, context: &mut Context, args: &[&ToLua]) -> Option<LuaGeneric> {
+    pub fn call_singleret(&self, context: &mut Context, args: &[&dyn ToLua]) -> Option<LuaGeneric> {
+        self.tag.verify(context.get_state(), self.index);
         let mut result = self.call(context, args, 1);
         match result.len() {
             0 => None,
@@ -130,12 +183,14 @@ impl LuaFunction {
     }
 
     /// Same as call, but returns all return values.
-    pub fn call_multiret(&self, context: &mut Context, args: &[&ToLua]) -> Vec<LuaGeneric> {
+    pub fn call_multiret(&self, context: &mut Context, args: &[&dyn ToLua]) -> Vec<LuaGeneric> {
+        self.tag.verify(context.get_state(), self.index);
         self.call(context, args, MULTRET)
     }
 
     /// Same as call, but does not return any return values.
-    pub fn call_noret(&self, context: &mut Context, args: &[&ToLua]) {
+    pub fn call_noret(&self, context: &mut Context, args: &[&dyn ToLua]) {
+        self.tag.verify(context.get_state(), self.index);
         self.call(context, args, 0);
     }
 }
@@ -148,6 +203,7 @@ impl LuaStackable for LuaFunction {
 
 impl ToLua for LuaFunction {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -155,7 +211,10 @@ impl ToLua for LuaFunction {
 impl FromLua for LuaFunction {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaFunction> {
         if state.is_fn(index) {
-            Some(LuaFunction::new(index))
+            Some(LuaFunction {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
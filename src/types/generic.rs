@@ -1,5 +1,7 @@
 use lua::{Index, Type, ToLua, FromLua, State};
-use types::{LuaStackable};
+use check::HandleTag;
+use types::{LuaStackable, LuaNil, LuaBool, LuaInteger, LuaNumber, LuaString, LuaTable, LuaFunction,
+    LuaUserdata, LuaLightUserdata, LuaThread};
 use context::Context;
 
 /// Represents a generic lua value on the Lua stack
@@ -19,26 +21,89 @@ use context::Context;
 /// assert_eq!(Some(4), generic.get_value(&mut context));
 /// ```
 pub struct LuaGeneric {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaGeneric {
-    /// Create a new LuaGeneric given an index
-    pub fn new(i: Index) -> LuaGeneric {
+    /// Create a new LuaGeneric at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaGeneric {
+        let state = context.get_state();
         LuaGeneric {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Gets the Lua type of this generic object
     pub fn type_of(&self, context: &mut Context) -> Type {
-        context.get_state().type_of(self.index).unwrap()
+        self.tag.verify(context.get_state(), self.index);
+        context.get_state().type_of(self.index).unwrap_or(Type::None)
     }
 
     /// Convert the contained value of this generic object to a given type
     pub fn get_value<T: FromLua>(&self, context: &mut Context) -> Option<T> {
+        self.tag.verify(context.get_state(), self.index);
         context.get_state().to_type(self.index)
     }
+
+    /// Convert this generic object to the typed handle that matches its type.
+    ///
+    /// Numbers are split into integers and floats, so a number that is represented as an integer
+    /// becomes a LuaInteger, and any other number becomes a LuaNumber. An index that does not
+    /// refer to a value, such as a missing argument, becomes `LuaVariant::None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// # use luaext::types::generic::{LuaGeneric, LuaVariant};
+    /// # let mut state = State::new();
+    /// # let mut context = Context::new(&mut state);
+    /// context.push_number(1.5);
+    /// let generic: LuaGeneric = context.get_arg(1).unwrap();
+    /// match generic.classify(&mut context) {
+    ///     LuaVariant::Number(number) => assert_eq!(1.5, number.get(&mut context)),
+    ///     _ => panic!("expected a float"),
+    /// }
+    /// ```
+    pub fn classify(&self, context: &mut Context) -> LuaVariant {
+        let index = self.index;
+        match self.type_of(context) {
+            Type::Boolean => LuaVariant::Bool(context.handle(index)),
+            Type::Number if context.get_state().is_integer(index) => {
+                LuaVariant::Integer(context.handle(index))
+            },
+            Type::Number => LuaVariant::Number(context.handle(index)),
+            Type::String => LuaVariant::String(context.handle(index)),
+            Type::Table => LuaVariant::Table(context.handle(index)),
+            Type::Function => LuaVariant::Function(context.handle(index)),
+            Type::Userdata => LuaVariant::Userdata(context.handle(index)),
+            Type::LightUserdata => LuaVariant::LightUserdata(context.handle(index)),
+            Type::Thread => LuaVariant::Thread(context.handle(index)),
+            Type::Nil => LuaVariant::Nil(context.handle(index)),
+            Type::None => LuaVariant::None,
+        }
+    }
+}
+
+/// A value on the Lua stack, classified by its type
+///
+/// Returned by `LuaGeneric::classify`.
+pub enum LuaVariant {
+    /// There is no value at the index
+    None,
+    Nil(LuaNil),
+    Bool(LuaBool),
+    Integer(LuaInteger),
+    Number(LuaNumber),
+    String(LuaString),
+    Table(LuaTable),
+    Function(LuaFunction),
+    Userdata(LuaUserdata),
+    LightUserdata(LuaLightUserdata),
+    Thread(LuaThread),
 }
 
 impl LuaStackable for LuaGeneric {
@@ -49,12 +114,16 @@ impl LuaStackable for LuaGeneric {
 
 impl ToLua for LuaGeneric {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
 
 impl FromLua for LuaGeneric {
-    fn from_lua(_: &mut State, index: Index) -> Option<LuaGeneric> {
-        Some(LuaGeneric::new(index))
+    fn from_lua(state: &mut State, index: Index) -> Option<LuaGeneric> {
+        Some(LuaGeneric {
+            index: state.abs_index(index),
+            tag: HandleTag::current(state),
+        })
     }
 }
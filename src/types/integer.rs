@@ -1,22 +1,27 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
 use types::{LuaStackable};
 use context::Context;
 
 /// Represents an integer on the Lua Stack
 pub struct LuaInteger {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaInteger {
-    /// Create a new LuaInteger given an index
-    pub fn new(i: Index) -> LuaInteger {
+    /// Create a new LuaInteger at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaInteger {
+        let state = context.get_state();
         LuaInteger {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Get the value of this integer
     pub fn get(&self, context: &mut Context) -> i64 {
+        self.tag.verify(context.get_state(), self.index);
         context.get_state().to_integer(self.get_pos())
     }
 }
@@ -29,6 +34,7 @@ impl LuaStackable for LuaInteger {
 
 impl ToLua for LuaInteger {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -36,7 +42,10 @@ impl ToLua for LuaInteger {
 impl FromLua for LuaInteger {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaInteger> {
         if state.is_integer(index) {
-            Some(LuaInteger::new(index))
+            Some(LuaInteger {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
@@ -1,20 +1,34 @@
 use lua::{Index, ToLua, FromLua, State};
+use lua::libc::c_void;
+use std::marker::PhantomData;
+use check::HandleTag;
+use context::Context;
 use types::LuaStackable;
 
 /// Represents a pointer on the Lua stack
 ///
-/// Note that you can not retrive the value of light userdata from Lua.
+/// Light userdata are pushed with `Context::push_light_userdata`, and the pointer can be
+/// retrieved again with `as_ptr`.
 pub struct LuaLightUserdata {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaLightUserdata {
-    /// Create a new LuaLightUserdata at the given index
-    pub fn new(i: Index) -> LuaLightUserdata {
+    /// Create a new LuaLightUserdata at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaLightUserdata {
+        let state = context.get_state();
         LuaLightUserdata {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
+
+    /// Get the pointer stored in this light userdata.
+    pub fn as_ptr(&self, context: &mut Context) -> *mut c_void {
+        self.tag.verify(context.get_state(), self.index);
+        context.get_state().to_userdata(self.index)
+    }
 }
 
 impl LuaStackable for LuaLightUserdata {
@@ -25,6 +39,7 @@ impl LuaStackable for LuaLightUserdata {
 
 impl ToLua for LuaLightUserdata {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -32,9 +47,80 @@ impl ToLua for LuaLightUserdata {
 impl FromLua for LuaLightUserdata {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaLightUserdata> {
         if state.is_light_userdata(index) {
-            Some(LuaLightUserdata::new(index))
+            Some(LuaLightUserdata {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
     }
 }
+
+/// A registry key that can not collide with any other key.
+///
+/// The key is the address of the RegistryKey itself, pushed as a light userdata, so a key
+/// should be kept in a `static`; this is the usual way for a library to keep private entries in
+/// the registry. The type parameter is the type of the value stored under the key.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::lua::State;
+/// # use luaext::context::Context;
+/// use luaext::types::RegistryKey;
+///
+/// static COUNTER: RegistryKey<i64> = RegistryKey::new();
+///
+/// # let mut state = State::new();
+/// # let mut context = Context::new(&mut state);
+/// COUNTER.set(&mut context, &1);
+/// context.set_in_registry(&"counter", &2);
+/// assert_eq!(Some(1), COUNTER.get(&mut context));
+/// ```
+pub struct RegistryKey<T> {
+    // a zero-sized static may share its address with another static
+    _address: u8,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> RegistryKey<T> {
+    /// Create a new registry key.
+    pub const fn new() -> RegistryKey<T> {
+        RegistryKey {
+            _address: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    fn as_ptr(&self) -> *mut c_void {
+        self as *const RegistryKey<T> as *mut c_void
+    }
+}
+
+impl<T> Default for RegistryKey<T> {
+    fn default() -> RegistryKey<T> {
+        RegistryKey::new()
+    }
+}
+
+impl<T: ToLua> RegistryKey<T> {
+    /// Store a value in the registry under this key.
+    pub fn set(&self, context: &mut Context, value: &T) {
+        context.set_in_registry(self, value);
+    }
+}
+
+impl<T: FromLua> RegistryKey<T> {
+    /// Get the value stored in the registry under this key, or None if there is no value or it
+    /// is not a T.
+    pub fn get(&self, context: &mut Context) -> Option<T> {
+        context.get_from_registry_typed(self)
+    }
+}
+
+impl<T> ToLua for RegistryKey<T> {
+    fn to_lua(&self, state: &mut State) {
+        unsafe { state.push_light_userdata(self.as_ptr()) };
+    }
+}
@@ -1,4 +1,12 @@
-use lua::{Index};
+//! Handles to values on the Lua stack.
+//!
+//! A handle stores the position of its value as an absolute index, so that it keeps referring
+//! to the same value when more values are pushed. Handles created from a relative (negative)
+//! index are converted to an absolute index using `lua_absindex`. A handle may also store a
+//! pseudo-index, which refers to a value that is not on the stack, such as the registry (see
+//! `Context::get_registry`) or an upvalue of the running function (see `Context::get_upvalue`).
+
+use lua::{Index, REGISTRYINDEX};
 
 pub mod number;
 pub mod string;
@@ -15,15 +23,28 @@ pub mod thread;
 pub use self::number::LuaNumber;
 pub use self::string::LuaString;
 pub use self::table::LuaTable;
-pub use self::generic::LuaGeneric;
+pub use self::generic::{LuaGeneric, LuaVariant};
 pub use self::nil::LuaNil;
 pub use self::integer::LuaInteger;
 pub use self::boolean::LuaBool;
 pub use self::function::LuaFunction;
 pub use self::userdata::LuaUserdata;
-pub use self::ltuserdata::LuaLightUserdata;
+pub use self::ltuserdata::{LuaLightUserdata, RegistryKey};
 pub use self::thread::LuaThread;
 
+/// Get the pseudo-index of the nth upvalue of the running function.
+///
+/// Upvalues are numbered starting from 1.
+pub fn upvalue_index(n: Index) -> Index {
+    REGISTRYINDEX - n
+}
+
+/// Check if an index is a pseudo-index, which refers to either the registry or an upvalue
+/// rather than a position on the stack.
+pub fn is_pseudo_index(i: Index) -> bool {
+    i <= REGISTRYINDEX
+}
+
 /// Any value that can represent an Index on a Lua Stack
 pub trait LuaStackable {
     /// Get the position of this value on the stack
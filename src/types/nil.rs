@@ -1,16 +1,21 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
+use context::Context;
 use types::{LuaStackable};
 
 /// Represents a nil value on the Lua stack
 pub struct LuaNil {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaNil {
-    /// Create a new LuaNil given an index
-    pub fn new(i: Index) -> LuaNil {
+    /// Create a new LuaNil at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaNil {
+        let state = context.get_state();
         LuaNil {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 }
@@ -23,6 +28,7 @@ impl LuaStackable for LuaNil {
 
 impl ToLua for LuaNil {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -30,7 +36,10 @@ impl ToLua for LuaNil {
 impl FromLua for LuaNil {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaNil> {
         if state.is_nil(index) {
-            Some(LuaNil::new(index))
+            Some(LuaNil {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
@@ -1,22 +1,27 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
 use types::{LuaStackable};
 use context::Context;
 
 /// Represents a floating-point number on the Lua Stack
 pub struct LuaNumber {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaNumber {
-    /// Create a new LuaNumber given an index
-    pub fn new(i: Index) -> LuaNumber {
+    /// Create a new LuaNumber at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaNumber {
+        let state = context.get_state();
         LuaNumber {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Get the value of this number
     pub fn get(&self, context: &mut Context) -> f64 {
+        self.tag.verify(context.get_state(), self.index);
         context.get_state().to_number(self.get_pos())
     }
 }
@@ -29,6 +34,7 @@ impl LuaStackable for LuaNumber {
 
 impl ToLua for LuaNumber {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -36,7 +42,10 @@ impl ToLua for LuaNumber {
 impl FromLua for LuaNumber {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaNumber> {
         if state.is_number(index) {
-            Some(LuaNumber::new(index))
+            Some(LuaNumber {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
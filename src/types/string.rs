@@ -1,23 +1,28 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
 use types::{LuaStackable};
 use context::Context;
 
 /// Represents a String on the Lua Stack
 pub struct LuaString {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaString {
-    /// Create a new String given an index
-    pub fn new(i: Index) -> LuaString {
+    /// Create a new LuaString at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaString {
+        let state = context.get_state();
         LuaString {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Get the value of this string
     pub fn get<'a>(&self, context: &'a mut Context) -> &'a str {
-        context.get_state().to_str(self.get_pos()).unwrap()
+        self.tag.verify(context.get_state(), self.index);
+        context.get_state().to_str_in_place(self.get_pos()).unwrap()
     }
 }
 
@@ -29,6 +34,7 @@ impl LuaStackable for LuaString {
 
 impl ToLua for LuaString {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -36,7 +42,10 @@ impl ToLua for LuaString {
 impl FromLua for LuaString {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaString> {
         if state.is_string(index) {
-            Some(LuaString::new(index))
+            Some(LuaString {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
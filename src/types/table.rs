@@ -1,17 +1,22 @@
 use lua::{Index, ToLua, FromLua, State, Type};
+use check::HandleTag;
 use types::{LuaStackable, LuaGeneric};
 use context::Context;
+use protect;
 
 /// Represents a Lua table on the Lua stack.
 pub struct LuaTable {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaTable {
-    /// Create a new LuaTable at the given index.
-    pub fn new(i: Index) -> LuaTable {
+    /// Create a new LuaTable at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaTable {
+        let state = context.get_state();
         LuaTable {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
@@ -19,7 +24,9 @@ impl LuaTable {
     ///
     /// Equivalent to the Lua `setmetatable` function
     pub fn set_metatable(&self, context: &mut Context, meta: &LuaTable) {
-        context.get_state().push_value(meta.get_pos());
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(1);
+        meta.to_lua(context.get_state());
         context.get_state().set_metatable(self.get_pos());
     }
 
@@ -27,16 +34,20 @@ impl LuaTable {
     /// May call the __newindex metamethod
     ///
     /// Equivalent to `table[key] = value` in Lua
-    pub fn set(&self, context: &mut Context, key: &ToLua, value: &ToLua) {
+    pub fn set(&self, context: &mut Context, key: &dyn ToLua, value: &dyn ToLua) {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(4);
         key.to_lua(context.get_state());
         value.to_lua(context.get_state());
-        context.get_state().set_table(self.get_pos());
+        protect::set_table_at(context.get_state(), self.get_pos());
     }
 
     /// Set a value in this table without invoking metamethods
     ///
     /// Equivalent to the Lua `rawset` function
-    pub fn set_raw(&self, context: &mut Context, key: &ToLua, value: &ToLua) {
+    pub fn set_raw(&self, context: &mut Context, key: &dyn ToLua, value: &dyn ToLua) {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(2);
         key.to_lua(context.get_state());
         value.to_lua(context.get_state());
         context.get_state().raw_set(self.get_pos());
@@ -46,25 +57,31 @@ impl LuaTable {
     /// May call the __index metamethod
     ///
     /// Equivalent to `table[key]` in Lua
-    pub fn get(&self, context: &mut Context, key: &ToLua) -> LuaGeneric {
+    pub fn get(&self, context: &mut Context, key: &dyn ToLua) -> LuaGeneric {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(3);
         key.to_lua(context.get_state());
-        context.get_state().get_table(self.get_pos());
-        LuaGeneric::new(context.get_state().get_top())
+        protect::get_table_at(context.get_state(), self.get_pos());
+        context.handle(-1)
     }
 
     /// Get a value from this table without invoking metamethods
     ///
     /// Equivalent to the Lua `rawget` function
-    pub fn get_raw(&self, context: &mut Context, key: &ToLua) -> LuaGeneric {
+    pub fn get_raw(&self, context: &mut Context, key: &dyn ToLua) -> LuaGeneric {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(1);
         key.to_lua(context.get_state());
         context.get_state().raw_get(self.get_pos());
-        LuaGeneric::new(context.get_state().get_top())
+        context.handle(-1)
     }
 
     /// Get a value from this table as type T
-    pub fn get_typed<T: FromLua>(&self, context: &mut Context, key: &ToLua) -> Option<T> {
+    pub fn get_typed<T: FromLua>(&self, context: &mut Context, key: &dyn ToLua) -> Option<T> {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(3);
         key.to_lua(context.get_state());
-        context.get_state().get_table(self.get_pos());
+        protect::get_table_at(context.get_state(), self.get_pos());
         let top = context.get_state().get_top();
         let ret = context.get_state().to_type(top);
         ret
@@ -78,15 +95,18 @@ impl LuaTable {
     /// # Panics
     ///
     /// This method will panic if this table has a `__len`
-    /// metamethod that does not return an integer
+    /// metamethod that does not return an integer, or that raises an error
     pub fn len(&self, context: &mut Context) -> i64 {
-        context.get_state().len_direct(self.get_pos())
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(2);
+        protect::len_at(context.get_state(), self.get_pos())
     }
 
     /// Count the number of elements in this table as an array without calling the `__len` metamethod
     ///
     /// Equivalent to the Lua `rawlen` function
     pub fn len_raw(&self, context: &mut Context) -> usize {
+        self.tag.verify(context.get_state(), self.index);
         context.get_state().raw_len(self.get_pos())
     }
 
@@ -96,6 +116,7 @@ impl LuaTable {
     /// Similar to the Lua `ipairs` function
     pub fn iter_array<F>(&self, context: &mut Context, mut func: F)
             where F: FnMut(Context, i64, LuaGeneric) {
+        self.tag.verify(context.get_state(), self.index);
         for key in 1.. {
             let mut new_context = context.push_context();
             let value = self.get(&mut new_context, &key);
@@ -110,7 +131,8 @@ impl LuaTable {
     /// Add an element to the end of the table
     ///
     /// Equivalent to the Lua `table.insert` function
-    pub fn append(&self, context: &mut Context, value: &ToLua) {
+    pub fn append(&self, context: &mut Context, value: &dyn ToLua) {
+        self.tag.verify(context.get_state(), self.index);
         let length = self.len_raw(context);
         self.set(context, &(length as i64+1), value);
     }
@@ -124,6 +146,7 @@ impl LuaStackable for LuaTable {
 
 impl ToLua for LuaTable {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -131,7 +154,10 @@ impl ToLua for LuaTable {
 impl FromLua for LuaTable {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaTable> {
         if state.is_table(index) {
-            Some(LuaTable::new(index))
+            Some(LuaTable {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
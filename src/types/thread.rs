@@ -1,22 +1,27 @@
 use lua::{Index, ToLua, FromLua, State};
+use check::HandleTag;
 use types::LuaStackable;
 use context::Context;
 
 /// Represents a Lua thread on the Lua stack
 pub struct LuaThread {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaThread {
-    /// Create a new LuaThread at the given index
-    pub fn new(i: Index) -> LuaThread {
+    /// Create a new LuaThread at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaThread {
+        let state = context.get_state();
         LuaThread {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Get the contained State
     pub fn as_state(&self, context: &mut Context) -> State {
+        self.tag.verify(context.get_state(), self.index);
         // Unwrapping here is safe because LuaThread is guaranteed to refer to a State
         context.get_state().to_thread(self.index).unwrap()
     }
@@ -30,6 +35,7 @@ impl LuaStackable for LuaThread {
 
 impl ToLua for LuaThread {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -37,7 +43,10 @@ impl ToLua for LuaThread {
 impl FromLua for LuaThread {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaThread> {
         if state.is_thread(index) {
-            Some(LuaThread::new(index))
+            Some(LuaThread {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
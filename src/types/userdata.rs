@@ -1,6 +1,10 @@
 use lua::{Index, ToLua, FromLua, State};
-use types::{LuaStackable, LuaTable};
+use std::panic;
+use check::HandleTag;
+use types::{LuaStackable, LuaTable, LuaGeneric};
 use context::Context;
+use userdata::{self, LuaUserData, UserdataSlot, UserdataRef, UserdataRefMut};
+use error;
 
 /// Represents user-defined data on the Lua stack
 ///
@@ -8,36 +12,222 @@ use context::Context;
 /// Drop trait will not be dropped, which although not unsafe, may result in memory leaks.
 /// This includes types such as HashMap and Vec.
 pub struct LuaUserdata {
-    index: Index
+    index: Index,
+    tag: HandleTag,
 }
 
 impl LuaUserdata {
-    /// Create a new LuaUserdata at the given index
-    pub fn new(i: Index) -> LuaUserdata {
+    /// Create a new LuaUserdata at the given index, which may be relative to the top of the stack.
+    pub fn new(context: &mut Context, i: Index) -> LuaUserdata {
+        let state = context.get_state();
         LuaUserdata {
-            index: i
+            index: state.abs_index(i),
+            tag: HandleTag::current(state),
         }
     }
 
     /// Set this userdata's metatable.
     pub fn set_metatable(&self, context: &mut Context, meta: &LuaTable) {
-        context.get_state().push_value(meta.get_pos());
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(1);
+        meta.to_lua(context.get_state());
         context.get_state().set_metatable(self.get_pos());
     }
 
+    /// Check if this userdata was created by `Context::push_userdata` with a value of type T.
+    pub fn is<T: LuaUserData>(&self, context: &mut Context) -> bool {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(2);
+        let key = userdata::metatable_key::<T>();
+        !context.get_state().test_userdata(self.index, &key).is_null()
+    }
+
+    /// Borrow this userdata's value, or return None if it does not contain a T.
+    ///
+    /// A userdata of a class that extends T with `LuaClass::extends` is borrowed through the
+    /// class's upcast. The value stays borrowed until the returned guard is dropped. The guard
+    /// must not outlive the userdata's place on the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a `LuaError` payload if the value is mutably borrowed. In a Rust function
+    /// called by Lua, the panic is raised as the Lua error "userdata already mutably borrowed".
+    pub fn borrow<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> Option<UserdataRef<'a, T>> {
+        let slot = self.get_slot::<T>(context)?;
+        match slot.try_borrow() {
+            Ok(value) => Some(value),
+            Err(message) => raise(message),
+        }
+    }
+
+    /// Mutably borrow this userdata's value, or return None if it does not contain a T.
+    ///
+    /// The value stays borrowed until the returned guard is dropped. The guard must not outlive
+    /// the userdata's place on the stack.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a `LuaError` payload if the value is already borrowed. In a Rust function
+    /// called by Lua, the panic is raised as a Lua error.
+    pub fn borrow_mut<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> Option<UserdataRefMut<'a, T>> {
+        let slot = self.get_slot::<T>(context)?;
+        match slot.try_borrow_mut() {
+            Ok(value) => Some(value),
+            Err(message) => raise(message),
+        }
+    }
+
+    /// Same as `borrow`, but returns an error instead of panicking, and if the userdata does not
+    /// contain a T.
+    pub fn try_borrow<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> error::Result<UserdataRef<'a, T>> {
+        let slot = self.get_slot_or_err::<T>(context)?;
+        slot.try_borrow().or_else(|message| {
+            error::new_luaresult_err(error::LuaErrorType::RuntimeError, message.to_string())
+        })
+    }
+
+    /// Same as `borrow_mut`, but returns an error instead of panicking, and if the userdata
+    /// does not contain a T.
+    pub fn try_borrow_mut<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> error::Result<UserdataRefMut<'a, T>> {
+        let slot = self.get_slot_or_err::<T>(context)?;
+        slot.try_borrow_mut().or_else(|message| {
+            error::new_luaresult_err(error::LuaErrorType::RuntimeError, message.to_string())
+        })
+    }
+
+    fn get_slot<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> Option<UserdataSlot<'a, T>> {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(userdata::SLOT_STACK_SPACE);
+        unsafe { userdata::get_slot(context.get_state(), self.index) }
+    }
+
+    fn get_slot_or_err<'a, T: LuaUserData>(&'a self, context: &mut Context)
+            -> error::Result<UserdataSlot<'a, T>> {
+        match self.get_slot::<T>(context) {
+            Some(slot) => error::new_luaresult_ok(slot),
+            None => error::new_luaresult_err(error::LuaErrorType::TypeError,
+                format!("userdata is not a {}", T::type_name())),
+        }
+    }
+
+    /// Set this userdata's user value, which is kept alive for as long as the userdata is.
+    ///
+    /// Unlike a value stored in the registry, a user value is only reachable through its
+    /// userdata, so the garbage collector can collect cycles between the two, such as a
+    /// callback that refers to the userdata it is stored in.
+    pub fn set_user_value(&self, context: &mut Context, value: &dyn ToLua) {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(1);
+        value.to_lua(context.get_state());
+        context.get_state().set_uservalue(self.index);
+    }
+
+    /// Push this userdata's user value onto the stack, which is nil if it was never set.
+    pub fn get_user_value(&self, context: &mut Context) -> LuaGeneric {
+        self.tag.verify(context.get_state(), self.index);
+        context.reserve(1);
+        context.get_state().get_uservalue(self.index);
+        let i = context.get_state().get_top();
+        context.get_arg(i).unwrap()
+    }
+
+    /// Get this userdata's user value as a table, setting it to a new table if it is not one.
+    ///
+    /// This is the usual way for the Rust value in a userdata to refer to Lua values: rather
+    /// than keeping references in the registry, which are never collected while the Rust value
+    /// exists, the values are stored in the user table, by a key that the Rust value knows.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use luaext::lua::State;
+    /// # use luaext::context::Context;
+    /// use luaext::class::LuaClass;
+    /// use luaext::types::{LuaFunction, LuaUserdata};
+    /// use luaext::userdata::LuaUserData;
+    ///
+    /// struct Button {
+    ///     label: String,
+    /// }
+    ///
+    /// impl LuaUserData for Button {}
+    ///
+    /// # let mut state = State::new();
+    /// # let mut context = Context::new(&mut state);
+    /// LuaClass::<Button>::new("Button")
+    ///     .constructor("new", |_, (label,): (String,)| Ok(Button { label }))
+    ///     .method("label", |_, this, ()| Ok((this.label.clone(),)))
+    ///     .method("on_click", |context, _, (callback,): (LuaFunction,)| {
+    ///         // the first argument of a method is the userdata itself
+    ///         let button: LuaUserdata = context.get_arg_typed(1).unwrap();
+    ///         button.user_table(context).set(context, &"on_click", &callback);
+    ///         Ok(())
+    ///     })
+    ///     .method("click", |context, _, ()| {
+    ///         let button: LuaUserdata = context.get_arg_typed(1).unwrap();
+    ///         let table = button.user_table(context);
+    ///         match table.get_typed::<LuaFunction>(context, &"on_click") {
+    ///             Some(callback) => callback.pcall_typed(context, (button,)),
+    ///             None => Ok(()),
+    ///         }
+    ///     })
+    ///     .register(&mut context);
+    ///
+    /// context.do_string("button = Button.new('ok')
+    ///     button:on_click(function(self) clicked = self:label() end)
+    ///     button:click()").unwrap();
+    /// let clicked = context.push_global("clicked").get_value::<String>(&mut context);
+    /// assert_eq!(clicked.as_ref().map(|s| s.as_str()), Some("ok"));
+    /// ```
+    pub fn user_table(&self, context: &mut Context) -> LuaTable {
+        if let Some(table) = self.get_user_value(context).get_value::<LuaTable>(context) {
+            return table;
+        }
+        let table = context.push_table();
+        self.set_user_value(context, &table);
+        table
+    }
+
     /// Get a mutable reference to this userdata's contained data.
+    ///
+    /// # Safety
+    ///
+    /// The contained data must actually be of type T.
     pub unsafe fn get_value<'a, T>(&self, context: &'a mut Context) -> Option<&'a mut T> {
-        context.get_state().to_userdata_typed(self.index)
+        self.tag.verify(context.get_state(), self.index);
+        let block = context.get_state().to_userdata(self.index);
+        userdata::userdata_ptr::<T>(block).as_mut()
     }
 
     /// Get a mutable reference to this userdata's contained data, given that its metatable
     /// matches the given name.
+    ///
+    /// # Safety
+    ///
+    /// Every userdata given the metatable 'name' must contain data of type T.
     pub unsafe fn get_value_named<'a, T>(&self, context: &'a mut Context, name: &str)
             -> Option<&'a mut T> {
-        context.get_state().test_userdata_typed(self.index, name)
+        self.tag.verify(context.get_state(), self.index);
+        let block = context.get_state().test_userdata(self.index, name);
+        userdata::userdata_ptr::<T>(block).as_mut()
     }
 }
 
+/// Panic with a `LuaError` payload with the given message.
+///
+/// Raising a Lua error instead would jump over the Rust frames that hold the borrows without
+/// running their destructors.
+fn raise(message: &str) -> ! {
+    let err = error::new_luaresult_err::<()>(error::LuaErrorType::RuntimeError,
+        message.to_string());
+    panic::panic_any(err.unwrap_err())
+}
+
 impl LuaStackable for LuaUserdata {
     fn get_pos(&self) -> Index {
         self.index
@@ -46,6 +236,7 @@ impl LuaStackable for LuaUserdata {
 
 impl ToLua for LuaUserdata {
     fn to_lua(&self, state: &mut State) {
+        self.tag.verify(state, self.index);
         state.push_value(self.get_pos());
     }
 }
@@ -53,7 +244,10 @@ impl ToLua for LuaUserdata {
 impl FromLua for LuaUserdata {
     fn from_lua(state: &mut State, index: Index) -> Option<LuaUserdata> {
         if state.is_userdata(index) {
-            Some(LuaUserdata::new(index))
+            Some(LuaUserdata {
+                index: state.abs_index(index),
+                tag: HandleTag::current(state),
+            })
         } else {
             None
         }
@@ -0,0 +1,375 @@
+//! Type-safe userdata.
+//!
+//! Every type that implements `LuaUserData` gets its own metatable, which is stored in the
+//! registry under a key derived from the type's `TypeId`. `Context::push_userdata` gives each
+//! new userdata the metatable for its type, so the type of a userdata can be checked by
+//! comparing its metatable, and its value is dropped when Lua collects it.
+//!
+//! Values are borrowed through guards that track borrows at runtime, like a `RefCell`, so two
+//! handles to the same userdata can not be used to create aliasing mutable references.
+//!
+//! # Examples
+//!
+//! ```
+//! # use luaext::lua::State;
+//! # use luaext::context::Context;
+//! use luaext::userdata::LuaUserData;
+//!
+//! struct Counter {
+//!     count: i64,
+//! }
+//!
+//! impl LuaUserData for Counter {}
+//!
+//! struct Timer;
+//!
+//! impl LuaUserData for Timer {}
+//!
+//! # let mut state = State::new();
+//! # let mut context = Context::new(&mut state);
+//! let counter = context.push_userdata(Counter { count: 3 });
+//! counter.borrow_mut::<Counter>(&mut context).unwrap().count += 1;
+//! assert_eq!(counter.borrow::<Counter>(&mut context).map(|c| c.count), Some(4));
+//! assert!(counter.borrow::<Timer>(&mut context).is_none());
+//! ```
+
+use lua::{State, Index, Type};
+use lua::libc::c_void;
+use std::any::{self, Any, TypeId};
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::{cmp, mem};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::ptr;
+use context::Context;
+
+/// Trait for Rust types that can be stored in a userdata.
+pub trait LuaUserData: Any {
+    /// The name of this type in Lua, which is stored in the `__name` field of its metatable.
+    fn type_name() -> &'static str where Self: Sized {
+        any::type_name::<Self>()
+    }
+}
+
+/// The contents of a userdata created with `Context::push_userdata`.
+///
+/// The value comes first, so that the userdata can still be read with `LuaUserdata::get_value`.
+#[repr(C)]
+pub(crate) struct UserdataCell<T> {
+    value: UnsafeCell<T>,
+    /// The number of shared borrows, or -1 if the value is mutably borrowed
+    borrow: Cell<isize>,
+}
+
+impl<T> UserdataCell<T> {
+    pub(crate) fn new(value: T) -> UserdataCell<T> {
+        UserdataCell {
+            value: UnsafeCell::new(value),
+            borrow: Cell::new(0),
+        }
+    }
+
+    /// Get the slot that borrows this cell's value.
+    pub(crate) fn slot(&self) -> UserdataSlot<'_, T> {
+        UserdataSlot {
+            value: self.value.get() as *mut c_void,
+            borrow: &self.borrow,
+            upcast: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Borrow the value, or return an error message if it is mutably borrowed.
+    pub(crate) fn try_borrow(&self) -> Result<UserdataRef<'_, T>, &'static str> {
+        self.slot().try_borrow()
+    }
+}
+
+/// The alignment of the memory that Lua allocates for a userdata, which is that of the
+/// `L_Umaxalign` union in lobject.h.
+fn lua_alignment() -> usize {
+    let pointer = cmp::max(mem::align_of::<*const c_void>(), mem::align_of::<i64>());
+    cmp::max(mem::align_of::<f64>(), pointer)
+}
+
+/// Push a new userdata that can hold a U, and return the address that the U belongs at.
+///
+/// Lua only aligns userdata to `lua_alignment`, so a more strictly aligned U is placed at the
+/// first suitable address of a larger block. The block never moves, so `userdata_ptr` finds the
+/// same address again.
+pub(crate) fn new_userdata<U>(state: &mut State) -> *mut U {
+    let padding = mem::align_of::<U>().saturating_sub(lua_alignment());
+    let block = state.new_userdata(mem::size_of::<U>() + padding);
+    userdata_ptr(block)
+}
+
+/// Get the address of the U in a userdata block allocated by `new_userdata`.
+pub(crate) fn userdata_ptr<U>(block: *mut c_void) -> *mut U {
+    if block.is_null() {
+        return ptr::null_mut();
+    }
+    let offset = (block as usize).wrapping_neg() & (mem::align_of::<U>() - 1);
+    (block as *mut u8).wrapping_add(offset) as *mut U
+}
+
+/// Get the address of the value and of the borrow flag of a userdata that contains a T.
+fn cell_parts<T>(block: *mut c_void) -> (*mut c_void, *const Cell<isize>) {
+    let cell = userdata_ptr::<UserdataCell<T>>(block);
+    unsafe { ((*cell).value.get() as *mut c_void, &(*cell).borrow as *const Cell<isize>) }
+}
+
+/// Converts a pointer to the value of a class to a pointer to the value of one of its parents.
+#[derive(Clone)]
+pub(crate) struct Upcast {
+    as_ref: Rc<dyn Fn(*const c_void) -> *const c_void>,
+    as_mut: Rc<dyn Fn(*mut c_void) -> *mut c_void>,
+}
+
+impl Upcast {
+    pub(crate) fn new<C, P, F, G>(as_ref: F, as_mut: G) -> Upcast
+            where C: 'static, P: 'static,
+                  F: Fn(&C) -> &P + Send + 'static,
+                  G: Fn(&mut C) -> &mut P + Send + 'static {
+        Upcast {
+            as_ref: Rc::new(move |ptr| {
+                as_ref(unsafe { &*(ptr as *const C) }) as *const P as *const c_void
+            }),
+            as_mut: Rc::new(move |ptr| {
+                as_mut(unsafe { &mut *(ptr as *mut C) }) as *mut P as *mut c_void
+            }),
+        }
+    }
+
+    /// Get an upcast that applies this upcast, and then `next`.
+    pub(crate) fn then(&self, next: &Upcast) -> Upcast {
+        let (first, then) = (self.as_ref.clone(), next.as_ref.clone());
+        let (first_mut, then_mut) = (self.as_mut.clone(), next.as_mut.clone());
+        Upcast {
+            as_ref: Rc::new(move |ptr| then(first(ptr))),
+            as_mut: Rc::new(move |ptr| then_mut(first_mut(ptr))),
+        }
+    }
+}
+
+/// The metatable field that holds the `ClassInfo` of a class with parents.
+pub(crate) const CLASS_FIELD: &str = "__luaext_class";
+
+/// Describes how the values of a class can be borrowed as the values of its parents.
+pub(crate) struct ClassInfo {
+    parts: fn(*mut c_void) -> (*mut c_void, *const Cell<isize>),
+    /// The registry key of the metatable of the class, which `parts` is only valid for
+    metatable_key: String,
+    /// Upcasts to every parent of the class, by the parent's type
+    pub(crate) upcasts: HashMap<TypeId, Upcast>,
+}
+
+impl ClassInfo {
+    pub(crate) fn new<T: LuaUserData>(upcasts: HashMap<TypeId, Upcast>) -> ClassInfo {
+        ClassInfo {
+            parts: cell_parts::<T>,
+            metatable_key: metatable_key::<T>(),
+            upcasts,
+        }
+    }
+}
+
+impl LuaUserData for ClassInfo {
+    fn type_name() -> &'static str {
+        "luaext.class"
+    }
+}
+
+/// A userdata's value and its borrow flag, which may be borrowed as a T.
+pub(crate) struct UserdataSlot<'a, T> {
+    value: *mut c_void,
+    borrow: &'a Cell<isize>,
+    /// Converts the value to a T, if the value is not a T itself
+    upcast: Option<&'a Upcast>,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T> UserdataSlot<'a, T> {
+    /// Borrow the value, or return an error message if it is mutably borrowed.
+    pub(crate) fn try_borrow(&self) -> Result<UserdataRef<'a, T>, &'static str> {
+        match self.borrow.get() {
+            -1 => Err("userdata already mutably borrowed"),
+            n => {
+                self.borrow.set(n + 1);
+                let value = match self.upcast {
+                    Some(upcast) => (upcast.as_ref)(self.value),
+                    None => self.value,
+                };
+                Ok(UserdataRef {
+                    value: unsafe { &*(value as *const T) },
+                    borrow: self.borrow,
+                })
+            }
+        }
+    }
+
+    /// Mutably borrow the value, or return an error message if it is borrowed.
+    pub(crate) fn try_borrow_mut(&self) -> Result<UserdataRefMut<'a, T>, &'static str> {
+        match self.borrow.get() {
+            0 => {
+                self.borrow.set(-1);
+                let value = match self.upcast {
+                    Some(upcast) => (upcast.as_mut)(self.value),
+                    None => self.value,
+                };
+                Ok(UserdataRefMut {
+                    value: unsafe { &mut *(value as *mut T) },
+                    borrow: self.borrow,
+                })
+            },
+            -1 => Err("userdata already mutably borrowed"),
+            _ => Err("userdata already borrowed"),
+        }
+    }
+}
+
+/// Get the cell of the userdata at the given index, if it was created with a value of type T.
+///
+/// # Safety
+///
+/// The returned reference is only valid for as long as the userdata is alive.
+pub(crate) unsafe fn get_cell<'a, T: LuaUserData>(state: &mut State, index: Index)
+        -> Option<&'a UserdataCell<T>> {
+    let key = metatable_key::<T>();
+    // the metatable for T is only ever given to userdata that contain a T
+    let ptr = userdata_ptr::<UserdataCell<T>>(state.test_userdata(index, &key));
+    ptr.as_ref()
+}
+
+/// The most values that `get_slot` pushes onto the stack at once: the userdata's metatable, the
+/// class info and the two metatables that `get_cell` compares.
+pub(crate) const SLOT_STACK_SPACE: Index = 4;
+
+/// Get the slot of the userdata at the given index, if it contains a T or a value of a class
+/// that extends T.
+///
+/// There must be room on the stack for `SLOT_STACK_SPACE` more values.
+///
+/// # Safety
+///
+/// The returned slot is only valid for as long as the userdata is alive, and its metatable is
+/// not changed.
+pub(crate) unsafe fn get_slot<'a, T: LuaUserData>(state: &mut State, index: Index)
+        -> Option<UserdataSlot<'a, T>> {
+    if let Some(cell) = get_cell::<T>(state, index) {
+        return Some(cell.slot());
+    }
+    if state.type_of(index) != Some(Type::Userdata) || !state.get_metatable(index) {
+        return None;
+    }
+    state.push_string(CLASS_FIELD);
+    state.raw_get(-2);
+    let info: &'a ClassInfo = match get_cell::<ClassInfo>(state, -1) {
+        Some(info) => &*info.value.get(),
+        None => {
+            state.pop(2);
+            return None;
+        }
+    };
+    // a script can copy the field into another metatable, so the info is only trusted if the
+    // userdata has the metatable of the class that it describes
+    state.get_metatable_from_registry(&info.metatable_key);
+    let is_class = state.raw_equal(-1, -3);
+    state.pop(3);
+    if !is_class {
+        return None;
+    }
+    let upcast = info.upcasts.get(&TypeId::of::<T>())?;
+    let (value, borrow) = (info.parts)(state.to_userdata(index));
+    Some(UserdataSlot {
+        value,
+        borrow: &*borrow,
+        upcast: Some(upcast),
+        _marker: PhantomData,
+    })
+}
+
+/// A shared borrow of a userdata's value, which is released when dropped.
+pub struct UserdataRef<'a, T: 'a> {
+    value: &'a T,
+    borrow: &'a Cell<isize>,
+}
+
+impl<'a, T> Deref for UserdataRef<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T> Drop for UserdataRef<'a, T> {
+    fn drop(&mut self) {
+        self.borrow.set(self.borrow.get() - 1);
+    }
+}
+
+/// A mutable borrow of a userdata's value, which is released when dropped.
+pub struct UserdataRefMut<'a, T: 'a> {
+    value: &'a mut T,
+    borrow: &'a Cell<isize>,
+}
+
+impl<'a, T> Deref for UserdataRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T> DerefMut for UserdataRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'a, T> Drop for UserdataRefMut<'a, T> {
+    fn drop(&mut self) {
+        self.borrow.set(0);
+    }
+}
+
+/// Get the registry key of the metatable for the type T.
+pub(crate) fn metatable_key<T: Any>() -> String {
+    format!("luaext.userdata.{:?}", TypeId::of::<T>())
+}
+
+/// __gc metamethod for userdata created with `Context::push_userdata`.
+///
+/// Raises an error if the value is borrowed, which can only happen when a script calls the
+/// metamethod itself.
+pub(crate) fn userdata_gc<T: LuaUserData>(state: &mut State) -> i32 {
+    if let Some(cell) = unsafe { get_cell::<T>(state, 1) } {
+        if cell.borrow.get() != 0 {
+            state.push_string("can not collect a userdata that is borrowed");
+            state.error()
+        }
+        unsafe { ptr::drop_in_place(cell as *const UserdataCell<T> as *mut UserdataCell<T>) };
+        // the userdata can be resurrected by a finalizer, so make sure it is not dropped twice
+        state.push_nil();
+        state.set_metatable(1);
+    }
+    0
+}
+
+/// Push the metatable for the type T, creating it if it does not exist yet.
+///
+/// The metatable is protected, so `getmetatable` returns the type's name instead of it.
+pub(crate) fn push_metatable<T: LuaUserData>(context: &mut Context) {
+    context.reserve(2);
+    let key = metatable_key::<T>();
+    let state = context.get_state();
+    if state.new_metatable(&key) {
+        state.push_string(T::type_name());
+        state.set_field(-2, "__name");
+        state.push_string(T::type_name());
+        state.set_field(-2, "__metatable");
+        state.push_fn(lua_func!(userdata_gc<T>));
+        state.set_field(-2, "__gc");
+    }
+}
@@ -0,0 +1,304 @@
+//! Owned snapshots of Lua values.
+
+use lua::{Index, ToLua, FromLua, State, Type, Reference, REGISTRYINDEX};
+use lua::ffi::{self, lua_State};
+use lua::libc::c_void;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::slice;
+use context::Context;
+use types::{LuaUserdata, RegistryKey};
+use userdata::LuaUserData;
+use error;
+
+/// A reference to a Lua value that is kept alive in the registry.
+///
+/// The registry entry is released when the last clone of the reference is dropped, unless the
+/// State it belongs to has been closed already. A value referred to by a userdata is better kept
+/// in the userdata's user value (see `LuaUserdata::user_table`), which the garbage collector can
+/// see.
+#[derive(Clone, Debug)]
+pub struct LuaRef {
+    reference: Rc<RegistryEntry>,
+}
+
+#[derive(Debug)]
+struct RegistryEntry {
+    reference: Reference,
+    // the main thread, which lives as long as the State does
+    state: *mut lua_State,
+    alive: Arc<AtomicBool>,
+}
+
+impl Drop for RegistryEntry {
+    fn drop(&mut self) {
+        if self.alive.load(Ordering::SeqCst) {
+            unsafe { ffi::luaL_unref(self.state, REGISTRYINDEX, self.reference.value()) };
+        }
+    }
+}
+
+/// Set to false when the State that holds it is closed, which collects every object.
+///
+/// The flag is shared with references outside of the State, which may be on another thread than
+/// the State if the State is moved.
+struct StateToken(Arc<AtomicBool>);
+
+impl LuaUserData for StateToken {
+    fn type_name() -> &'static str {
+        "luaext.state"
+    }
+}
+
+impl Drop for StateToken {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+static STATE_TOKEN: RegistryKey<LuaUserdata> = RegistryKey::new();
+
+/// Get the flag that tells whether a State is still open.
+fn state_alive(state: &mut State) -> Arc<AtomicBool> {
+    let mut context = Context::new(state);
+    if let Some(token) = STATE_TOKEN.get(&mut context) {
+        if let Ok(token) = token.try_borrow::<StateToken>(&mut context) {
+            return token.0.clone();
+        }
+    }
+    let alive = Arc::new(AtomicBool::new(true));
+    let token = context.push_userdata(StateToken(alive.clone()));
+    STATE_TOKEN.set(&mut context, &token);
+    alive
+}
+
+impl LuaRef {
+    fn new(state: &mut State, index: Index) -> LuaRef {
+        state.check_stack_msg(2, "not enough stack space to reference value");
+        let alive = state_alive(state);
+        state.raw_geti(REGISTRYINDEX, ffi::LUA_RIDX_MAINTHREAD);
+        let main = unsafe { ffi::lua_tothread(state.as_ptr(), -1) };
+        state.pop(1);
+        state.push_value(index);
+        LuaRef {
+            reference: Rc::new(RegistryEntry {
+                reference: state.reference(REGISTRYINDEX),
+                state: main,
+                alive,
+            }),
+        }
+    }
+
+    /// Release this reference, if this is the last clone of it.
+    ///
+    /// This is the same as dropping the reference.
+    pub fn release(self, _: &mut Context) {}
+}
+
+impl PartialEq for LuaRef {
+    fn eq(&self, other: &LuaRef) -> bool {
+        self.reference.state == other.reference.state
+            && self.reference.reference == other.reference.reference
+    }
+}
+
+impl ToLua for LuaRef {
+    fn to_lua(&self, state: &mut State) {
+        state.raw_geti(REGISTRYINDEX, self.reference.reference.value() as i64);
+    }
+}
+
+/// An owned copy of a Lua value.
+///
+/// Unlike the types in the `types` module, a LuaValue does not refer to the stack, so it can be
+/// kept after its context is gone and pushed again later. Tables are copied entry by entry, with
+/// the array part first, in order, followed by the remaining entries in traversal order.
+/// Functions, userdata and threads can not be copied, so they are kept in the registry instead.
+///
+/// Tables are read and written with raw access, so metamethods are never called and metatables
+/// are not copied.
+///
+/// # Examples
+///
+/// ```
+/// # use luaext::lua::State;
+/// # use luaext::context::Context;
+/// # use luaext::value::LuaValue;
+/// # let mut state = State::new();
+/// # let mut context = Context::new(&mut state);
+/// context.do_string("point = {x = 1, y = 2.5}").unwrap();
+/// let point: LuaValue = context.push_global("point").get_value(&mut context).unwrap();
+/// context.set_global("copy", &point);
+/// context.do_string("y = copy.y").unwrap();
+/// assert_eq!(Some(2.5), context.push_global("y").get_value(&mut context));
+/// ```
+#[derive(Clone, Debug)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(Vec<u8>),
+    Table(Vec<(LuaValue, LuaValue)>),
+    LightUserdata(*mut c_void),
+    Function(LuaRef),
+    Userdata(LuaRef),
+    Thread(LuaRef),
+}
+
+impl LuaValue {
+    /// Copy the value at the given index.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is a table that contains itself, directly or through other
+    /// tables.
+    pub fn snapshot(context: &mut Context, index: Index) -> error::Result<LuaValue> {
+        let index = context.abs_index(index);
+        let mut visiting = Vec::new();
+        match read_value(context.get_state(), index, &mut visiting) {
+            Some(value) => error::new_luaresult_ok(value),
+            None => error::new_luaresult_err(error::LuaErrorType::TypeError,
+                "can not copy a table that contains itself".to_string()),
+        }
+    }
+
+    /// Release every registry reference held by this value.
+    ///
+    /// This is the same as dropping the value.
+    pub fn release(self, _: &mut Context) {}
+}
+
+impl PartialEq for LuaValue {
+    /// Tables are equal if they have the same keys with equal values, in any order.
+    fn eq(&self, other: &LuaValue) -> bool {
+        match (self, other) {
+            (LuaValue::Nil, LuaValue::Nil) => true,
+            (LuaValue::Boolean(a), LuaValue::Boolean(b)) => a == b,
+            (LuaValue::Integer(a), LuaValue::Integer(b)) => a == b,
+            (LuaValue::Number(a), LuaValue::Number(b)) => a == b,
+            (LuaValue::String(a), LuaValue::String(b)) => a == b,
+            (LuaValue::Table(a), LuaValue::Table(b)) => {
+                // keys are unique, so every key of a having an equal entry in b is enough
+                a.len() == b.len() && a.iter().all(|(key, value)| {
+                    b.iter().any(|(k, v)| k == key && v == value)
+                })
+            },
+            (LuaValue::LightUserdata(a), LuaValue::LightUserdata(b)) => a == b,
+            (LuaValue::Function(a), LuaValue::Function(b)) => a == b,
+            (LuaValue::Userdata(a), LuaValue::Userdata(b)) => a == b,
+            (LuaValue::Thread(a), LuaValue::Thread(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+fn read_bytes(state: &mut State, index: Index) -> Vec<u8> {
+    let mut len = 0;
+    unsafe {
+        let ptr = ffi::lua_tolstring(state.as_ptr(), index, &mut len);
+        slice::from_raw_parts(ptr as *const u8, len).to_vec()
+    }
+}
+
+/// Read the value at the absolute index `index`, returning None if a table cycle is found.
+fn read_value(state: &mut State, index: Index, visiting: &mut Vec<*const c_void>)
+        -> Option<LuaValue> {
+    Some(match state.type_of(index) {
+        None | Some(Type::None) | Some(Type::Nil) => LuaValue::Nil,
+        Some(Type::Boolean) => LuaValue::Boolean(state.to_bool(index)),
+        Some(Type::Number) => {
+            if state.is_integer(index) {
+                LuaValue::Integer(state.to_integer(index))
+            } else {
+                LuaValue::Number(state.to_number(index))
+            }
+        },
+        Some(Type::String) => LuaValue::String(read_bytes(state, index)),
+        Some(Type::LightUserdata) => LuaValue::LightUserdata(state.to_userdata(index)),
+        Some(Type::Function) => LuaValue::Function(LuaRef::new(state, index)),
+        Some(Type::Userdata) => LuaValue::Userdata(LuaRef::new(state, index)),
+        Some(Type::Thread) => LuaValue::Thread(LuaRef::new(state, index)),
+        Some(Type::Table) => return read_table(state, index, visiting),
+    })
+}
+
+fn read_table(state: &mut State, index: Index, visiting: &mut Vec<*const c_void>)
+        -> Option<LuaValue> {
+    let ptr = state.to_pointer(index);
+    if visiting.contains(&ptr) {
+        return None;
+    }
+    visiting.push(ptr);
+    state.check_stack_msg(3, "not enough stack space to copy table");
+    let mut entries = Vec::new();
+    // the array part, in order
+    let mut len = 0;
+    while state.raw_geti(index, len + 1) != Type::Nil {
+        let top = state.get_top();
+        let value = read_value(state, top, visiting);
+        state.pop(1);
+        entries.push((LuaValue::Integer(len + 1), value?));
+        len += 1;
+    }
+    state.pop(1);
+    // everything else
+    state.push_nil();
+    while state.next(index) {
+        let top = state.get_top();
+        let is_array_key = state.is_integer(top - 1) && {
+            let key = state.to_integer(top - 1);
+            key >= 1 && key <= len
+        };
+        if !is_array_key {
+            let entry = read_value(state, top - 1, visiting)
+                .and_then(|key| read_value(state, top, visiting).map(|value| (key, value)));
+            match entry {
+                Some(entry) => entries.push(entry),
+                None => {
+                    state.pop(2);
+                    return None;
+                }
+            }
+        }
+        state.pop(1);
+    }
+    visiting.pop();
+    Some(LuaValue::Table(entries))
+}
+
+impl ToLua for LuaValue {
+    fn to_lua(&self, state: &mut State) {
+        match *self {
+            LuaValue::Nil => state.push_nil(),
+            LuaValue::Boolean(b) => state.push_bool(b),
+            LuaValue::Integer(i) => state.push_integer(i),
+            LuaValue::Number(n) => state.push_number(n),
+            LuaValue::String(ref bytes) => unsafe {
+                ffi::lua_pushlstring(state.as_ptr(), bytes.as_ptr() as *const _, bytes.len());
+            },
+            LuaValue::Table(ref entries) => {
+                state.check_stack_msg(3, "not enough stack space to push table");
+                state.create_table(0, entries.len() as i32);
+                for (key, value) in entries {
+                    key.to_lua(state);
+                    value.to_lua(state);
+                    state.raw_set(-3);
+                }
+            },
+            LuaValue::LightUserdata(ptr) => unsafe { state.push_light_userdata(ptr) },
+            LuaValue::Function(ref r) | LuaValue::Userdata(ref r) | LuaValue::Thread(ref r) => {
+                r.to_lua(state)
+            },
+        }
+    }
+}
+
+impl FromLua for LuaValue {
+    /// Copy the value at the given index, returning None if it is a table that contains itself.
+    fn from_lua(state: &mut State, index: Index) -> Option<LuaValue> {
+        let index = state.abs_index(index);
+        read_value(state, index, &mut Vec::new())
+    }
+}